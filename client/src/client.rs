@@ -1,28 +1,114 @@
+use std::collections::HashMap;
 use std::path::PathBuf;
 
 use shared_utils::{
-    decode_header, decode_msg_type, encode_msg_type, MsgType, ServerMsg, ServerRes, MSG_SIZE_BYTES, UserMsg, MsgDataType,
+    decode_header, decode_msg_type, encode_msg_type, MsgDataType, MsgType, RequestId,
+    RequestKind, RequestMsg, ServerMsg, ServerRes, UserMsg, MSG_SIZE_BYTES, PING_INTERVAL,
 };
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream, fs::File,
+    net::{tcp::ReadHalf, TcpStream},
+    time::{Duration, Instant},
 };
 
 use iced_futures::futures::sink::SinkExt;
-use iced_futures::futures::{channel::mpsc, StreamExt};
+use iced_futures::futures::{
+    channel::{mpsc, oneshot},
+    StreamExt,
+};
 use iced_native::subscription::{self, Subscription};
 
+// Reconnect delays, doubled after every failed attempt
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+// Time the server has to answer a request, a login can take a few seconds after failed ones
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(20);
+// Time the server has to answer a ping before the connection is considered dead
+const PONG_TIMEOUT: Duration = Duration::from_secs(10);
+// Biggest msg read from the server, a broken header can't make the client allocate gigabytes.
+// Images are the biggest msgs, servers take 16MB ones by default
+const MAX_MSG_SIZE: usize = 64 * 1024 * 1024;
+
 #[derive(Debug, Clone)]
 pub enum Event {
     FailConnection,
     Connected(mpsc::Sender<Input>),
     MsgRecived(ServerMsg),
     ServerRes(ServerRes),
+    // Round trip time of the last ping
+    Latency(Duration),
 }
 
+#[derive(Debug, Clone)]
+pub enum RequestError {
+    Timeout,
+    // The connection was lost before the server answered
+    Disconnected,
+}
+
+pub type Reply = Result<ServerRes, RequestError>;
+
 pub enum Input {
     MsgType(MsgType),
-    ReadImgFile(PathBuf, String, String),
+    // Sent with a request id, the response with that id goes to the sender
+    Request(RequestKind, oneshot::Sender<ServerRes>),
+    // The msg data is replaced by the content of the file, then it's sent as a request
+    ReadImgFile(PathBuf, UserMsg, oneshot::Sender<ServerRes>),
+}
+
+// Sends the msg and waits for the server to answer it
+pub async fn request(sender: mpsc::Sender<Input>, msg: RequestKind) -> Reply {
+    send_request(sender, |responder| Input::Request(msg, responder)).await
+}
+
+pub async fn send_image(sender: mpsc::Sender<Input>, path: PathBuf, msg: UserMsg) -> Reply {
+    send_request(sender, |responder| Input::ReadImgFile(path, msg, responder)).await
+}
+
+async fn send_request(
+    mut sender: mpsc::Sender<Input>,
+    input: impl FnOnce(oneshot::Sender<ServerRes>) -> Input,
+) -> Reply {
+    let (responder, response) = oneshot::channel();
+    sender
+        .send(input(responder))
+        .await
+        .map_err(|_| RequestError::Disconnected)?;
+    match tokio::time::timeout(REQUEST_TIMEOUT, response).await {
+        Ok(Ok(res)) => Ok(res),
+        // The connection dropped the responder
+        Ok(Err(_)) => Err(RequestError::Disconnected),
+        Err(_) => Err(RequestError::Timeout),
+    }
+}
+
+// Wraps the msg with a new request id, its response goes to the responder
+fn start_request(
+    pending: &mut HashMap<RequestId, oneshot::Sender<ServerRes>>,
+    next_request_id: &mut RequestId,
+    msg: RequestKind,
+    responder: oneshot::Sender<ServerRes>,
+) -> MsgType {
+    *next_request_id += 1;
+    pending.retain(|_, responder| !responder.is_canceled());
+    pending.insert(*next_request_id, responder);
+    MsgType::Request(RequestMsg {
+        id: *next_request_id,
+        msg,
+    })
+}
+
+// None when the connection broke, or the msg is too big or can't be decoded
+async fn read_msg(reader: &mut ReadHalf<'_>) -> Option<MsgType> {
+    let mut header = [0; MSG_SIZE_BYTES];
+    reader.read_exact(&mut header).await.ok()?;
+    let len = decode_header(&header) as usize;
+    if len > MAX_MSG_SIZE {
+        return None;
+    }
+    let mut buf = vec![0; len];
+    reader.read_exact(&mut buf).await.ok()?;
+    decode_msg_type(&buf).ok()
 }
 
 pub enum State {
@@ -30,80 +116,145 @@ pub enum State {
     Connected(mpsc::Receiver<Input>, TcpStream),
 }
 
-pub fn connect() -> Subscription<Event> {
+// A new connection is made when the address changes
+pub fn connect(addr: String) -> Subscription<Event> {
     struct Connect;
 
     subscription::channel(
-        std::any::TypeId::of::<Connect>(),
+        (std::any::TypeId::of::<Connect>(), addr.clone()),
         100,
-        |mut output| async move {
-            let mut state = State::Disconnected;
-            let mut incoming_msg_len_buf = vec![0; MSG_SIZE_BYTES];
-
-            loop {
-                match &mut state {
-                    State::Disconnected => match TcpStream::connect("127.0.0.1:8000").await {
-                        Ok(socket) => {
-                            let (tx, rx) = mpsc::channel(100);
-                            let _ = output.send(Event::Connected(tx)).await;
-                            state = State::Connected(rx, socket);
-                        }
-                        Err(_) => {
-                            tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-                            let _ = output.send(Event::FailConnection).await;
+        move |mut output| {
+            let addr = addr.clone();
+            async move {
+                let mut state = State::Disconnected;
+                let mut retry_delay = INITIAL_RETRY_DELAY;
+                // Set by the server when it shuts down
+                let mut reconnect_after = None;
+                // Requests waiting for their response
+                let mut pending: HashMap<RequestId, oneshot::Sender<ServerRes>> = HashMap::new();
+                let mut next_request_id: RequestId = 0;
+                let mut next_ping = Instant::now();
+                let mut pings: u64 = 0;
+                // Number and send time of the ping waiting for its pong
+                let mut ping: Option<(u64, Instant)> = None;
+
+                loop {
+                    match &mut state {
+                        State::Disconnected => {
+                            // Their responses won't come
+                            pending.clear();
+                            if let Some(delay) = reconnect_after.take() {
+                                tokio::time::sleep(delay).await;
+                            }
+                            match TcpStream::connect(&addr).await {
+                                Ok(socket) => {
+                                    retry_delay = INITIAL_RETRY_DELAY;
+                                    next_ping = Instant::now() + PING_INTERVAL;
+                                    ping = None;
+                                    let (tx, rx) = mpsc::channel(100);
+                                    let _ = output.send(Event::Connected(tx)).await;
+                                    state = State::Connected(rx, socket);
+                                }
+                                Err(_) => {
+                                    tokio::time::sleep(retry_delay).await;
+                                    retry_delay = (retry_delay * 2).min(MAX_RETRY_DELAY);
+                                    let _ = output.send(Event::FailConnection).await;
+                                }
+                            }
                         }
-                    },
-                    State::Connected(rx, socket) => {
-                        let (mut reader, mut writer) = socket.split();
-
-                        tokio::select! {
-                            bytes_readed = reader.read(&mut incoming_msg_len_buf) => {
-                                if let Ok(bytes_readed) = bytes_readed {
-                                    if bytes_readed != 0 {
-                                        let incoming_msg_len = decode_header(&incoming_msg_len_buf[..]) as usize;
-                                        let mut buf = vec![0; incoming_msg_len];
-                                        let _ = reader.read(&mut buf).await.unwrap();
-
-                                        let recived_msg = decode_msg_type(&buf).unwrap();
-                                        match recived_msg {
-                                            MsgType::MsgIn(msg) => {
-                                                let _ = output.send(Event::MsgRecived(msg)).await;
-                                            },
-                                            MsgType::Server(msg) => {
-                                                let _ = output.send(Event::ServerRes(msg)).await;
-                                            },
-                                            _ => {}
+                        State::Connected(rx, socket) => {
+                            let (mut reader, mut writer) = socket.split();
+                            let mut peek_buf = [0; 1];
+                            let ping_deadline = match ping {
+                                Some((_, sent)) => sent + PONG_TIMEOUT,
+                                None => next_ping,
+                            };
+
+                            tokio::select! {
+                                // Peeking takes nothing from the socket, the other branches can win
+                                // the race without losing part of a msg
+                                peeked = reader.peek(&mut peek_buf) => {
+                                    let recived_msg = match peeked {
+                                        Ok(0) | Err(_) => None,
+                                        // A server that dies halfway through a msg never sends the rest
+                                        Ok(_) => tokio::time::timeout(PONG_TIMEOUT, read_msg(&mut reader))
+                                            .await
+                                            .ok()
+                                            .flatten(),
+                                    };
+                                    match recived_msg {
+                                        Some(MsgType::MsgIn(msg)) => {
+                                            let _ = output.send(Event::MsgRecived(msg)).await;
+                                        },
+                                        Some(MsgType::Server(msg)) => {
+                                            if let ServerRes::ServerShutdown(shutdown) = &msg {
+                                                reconnect_after = Some(Duration::from_secs(shutdown.reconnect_after.max(0) as u64));
+                                            }
+                                            let _ = output.send(Event::ServerRes(msg)).await;
+                                        },
+                                        Some(MsgType::Pong(nonce)) => {
+                                            if let Some((_, sent)) = ping.filter(|(sent_nonce, _)| *sent_nonce == nonce) {
+                                                let _ = output.send(Event::Latency(sent.elapsed())).await;
+                                                ping = None;
+                                                next_ping = Instant::now() + PING_INTERVAL;
+                                            }
+                                        },
+                                        Some(MsgType::Response(response)) => {
+                                            match pending.remove(&response.id) {
+                                                // Fails when the request timed out
+                                                Some(responder) => {
+                                                    let _ = responder.send(response.res);
+                                                }
+                                                None => {
+                                                    let _ = output.send(Event::ServerRes(response.res)).await;
+                                                }
+                                            }
+                                        },
+                                        Some(_) => {}
+                                        // Closed, stalled, or sent something that isn't a msg
+                                        None => {
+                                            let _ = output.send(Event::FailConnection).await;
+                                            state = State::Disconnected;
                                         }
-                                        continue;
                                     }
                                 }
-                                let _ = output.send(Event::FailConnection).await;
-                                state = State::Disconnected;
-                            }
-                            msg = rx.select_next_some() => {
-                                match msg {
-                                    Input::MsgType(msg) => {
-                                        if writer.write_all(&encode_msg_type(&msg)).await.is_err() {
+                                _ = tokio::time::sleep_until(ping_deadline) => {
+                                    match ping {
+                                        // A dead server or network never answers, writing could still succeed for a while
+                                        Some(_) => {
                                             let _ = output.send(Event::FailConnection).await;
                                             state = State::Disconnected;
                                         }
-                                    },
-                                    Input::ReadImgFile(path, username, token) => {
-                                        let mut f = File::open(path).await.unwrap();
-                                        let mut buf = Vec::new();
-                                        f.read_to_end(&mut buf).await.unwrap();
-                                        let msg = MsgType::MsgOut(UserMsg {
-                                            username: username,
-                                            data: MsgDataType::Image(buf),
-                                            token: token,
-                                        });
+                                        None => {
+                                            pings += 1;
+                                            ping = Some((pings, Instant::now()));
+                                            if writer.write_all(&encode_msg_type(&MsgType::Ping(pings))).await.is_err() {
+                                                let _ = output.send(Event::FailConnection).await;
+                                                state = State::Disconnected;
+                                            }
+                                        }
+                                    }
+                                }
+                                input = rx.select_next_some() => {
+                                    let msg = match input {
+                                        Input::MsgType(msg) => Some(msg),
+                                        Input::Request(msg, responder) => {
+                                            Some(start_request(&mut pending, &mut next_request_id, msg, responder))
+                                        }
+                                        Input::ReadImgFile(path, mut msg, responder) => match tokio::fs::read(path).await {
+                                            Ok(image) => {
+                                                msg.data = MsgDataType::Image(image);
+                                                Some(start_request(&mut pending, &mut next_request_id, RequestKind::MsgOut(msg), responder))
+                                            }
+                                            // Moved or deleted since it was picked, dropping the responder fails the send
+                                            Err(_) => None,
+                                        },
+                                    };
+                                    if let Some(msg) = msg {
                                         if writer.write_all(&encode_msg_type(&msg)).await.is_err() {
                                             let _ = output.send(Event::FailConnection).await;
                                             state = State::Disconnected;
                                         }
-                                        if let MsgType::MsgOut(msg) = msg {
-                                            let _ = output.send(Event::MsgRecived(ServerMsg { username: msg.username, data: msg.data })).await;
-                                        }
                                     }
                                 }
                             }
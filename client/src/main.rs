@@ -1,9 +1,17 @@
 mod client;
+mod profiles;
+mod session;
+mod storage;
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use once_cell::sync::Lazy;
 
 use iced::widget::{
-    button, column, container, row, scrollable, text, text_input, Button, Column, Text,
+    button, checkbox, column, container, horizontal_rule, row, scrollable, text, text_input,
+    Button, Column, Text,
 };
 use iced::{executor, Application, Command, Element, Length, Settings, Theme};
 use iced_aw::{Icon, ICON_FONT};
@@ -13,18 +21,32 @@ use iced_native::image::Handle;
 use iced_native::widget::image::Image;
 use iced_native::widget::Container;
 
-use shared_utils::{LoginMsg, MsgDataType, MsgType, ServerMsg, UserMsg};
+use shared_utils::{
+    DeleteMsg, ErrorCode, LoginMsg, ModerationAction, ModerationMsg, MsgDataType, MsgType,
+    Permission, ReadMarkerMsg, RequestKind, ResumeMsg, Role, ServerMsg, UserMsg, DEFAULT_ROOM,
+    MAX_MUTE,
+};
 
 use native_dialog::FileDialog;
 
+use profiles::{Profiles, ServerProfile};
+
 static MESSAGE_LOG: Lazy<scrollable::Id> = Lazy::new(scrollable::Id::unique);
 
+// Time a toast stays on screen
+const TOAST_TIME: Duration = Duration::from_secs(4);
+// Waits before resuming the session again after the server refused it or didn't answer,
+// doubled after every failed attempt
+const INITIAL_RESUME_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESUME_DELAY: Duration = Duration::from_secs(60);
+
 fn main() -> Result<(), iced::Error> {
     RustyChat::run(Settings::default())
 }
 
 #[derive(Debug, Clone)]
 enum Views {
+    Connect,
     SignupForm,
     LoginForm,
     Chat,
@@ -41,6 +63,55 @@ enum Messages {
     SubmitSignupForm,
     SubmitLoginForm,
     SubmitImg,
+    SelectRoom(String),
+    MessageLogScrolled(scrollable::RelativeOffset),
+    RetryOutgoing(usize),
+    CancelOutgoing(usize),
+    RememberMeToggled(bool),
+    Logout,
+    HostInput(String),
+    PortInput(String),
+    SubmitConnectForm,
+    SelectServer(ServerProfile),
+    RemoveServer(ServerProfile),
+    ChangeServer,
+    DeleteMsg(i64),
+    // Hides the toast with that id, unless a newer one replaced it
+    ToastExpired(usize),
+    // Answer to a login, signup or session resume
+    Response(client::Reply),
+    // Answer to the outgoing msg with the id
+    Sent(usize, client::Reply),
+    // Resumes the session again, unless a newer attempt was made since
+    RetryResume(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Delivery {
+    // Waiting for a connection
+    Queued,
+    // Handed to the connection, waiting for the server to send it back
+    Sending,
+    // The connection was lost or the server refused it, the user can retry or cancel it
+    Failed,
+}
+
+// A msg of the user not confirmed by the server yet
+#[derive(Debug, Clone)]
+struct Outgoing {
+    id: usize,
+    msg: UserMsg,
+    // Images are read from disk when sent
+    image: Option<PathBuf>,
+    delivery: Delivery,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Room {
+    unread: i64,
+    last_read_id: i64,
+    // Newest msg id known in the room, from the unread counts or the msgs received
+    newest_id: i64,
 }
 
 struct RustyChat {
@@ -53,7 +124,131 @@ struct RustyChat {
     username: String,
     password: String,
     error_msg: String,
+    // Shown under the input the server refused
+    username_error: String,
+    password_error: String,
+    // Short lived msg, e.g. when sending too fast
+    toast: String,
+    toast_id: usize,
     token: String,
+    room: String,
+    rooms: BTreeMap<String, Room>,
+    // Last read msg id when the current room was opened, the "new messages" divider goes after it
+    divider: Option<i64>,
+    at_bottom: bool,
+    notice: String,
+    // Waiting for the server to accept the stored token after a reconnect
+    resuming: bool,
+    resume_delay: Duration,
+    // Id of the last resume attempt, a scheduled retry for an older one does nothing
+    resume_attempt: usize,
+    outbox: Vec<Outgoing>,
+    next_outgoing_id: usize,
+    // Keep the token on disk to login automatically on the next launch
+    remember_me: bool,
+    // Server the subscription is connected to, none while choosing one
+    server: Option<ServerProfile>,
+    profiles: Profiles,
+    host: String,
+    port: String,
+    // Hides the actions the server would refuse
+    role: Role,
+    // Why the server closed the connection, shown until it's back
+    shutdown_notice: String,
+    // Round trip time of the last ping, none while disconnected
+    latency: Option<Duration>,
+}
+
+// Room a msg is shown in, direct msgs go to a "@username" room named after the other user
+fn conversation(msg: &ServerMsg, username: &str) -> String {
+    match &msg.to {
+        Some(to) if msg.username == username => format!("@{}", to),
+        Some(_) => format!("@{}", msg.username),
+        None => msg.room.clone(),
+    }
+}
+
+const MODERATION_USAGE: &str =
+    "Usage: /kick user, /mute user minutes, /ban user minutes|forever reason";
+const MUTE_USAGE: &str = "Mutes last from 1 minute up to a year: /mute user minutes";
+
+// "/kick user", "/mute user minutes" or "/ban user minutes|forever reason", none for other text
+fn parse_moderation(text: &str) -> Option<Result<(String, ModerationAction), &'static str>> {
+    let mut words = text.split_whitespace();
+    let command = words.next()?;
+    if !["/kick", "/mute", "/ban"].contains(&command) {
+        return None;
+    }
+    let Some(username) = words.next() else {
+        return Some(Err(MODERATION_USAGE));
+    };
+    let minutes = words.next();
+    let action = match (command, minutes) {
+        ("/kick", _) => ModerationAction::Kick,
+        ("/mute", Some(minutes)) => {
+            let duration = minutes
+                .parse::<i64>()
+                .ok()
+                .and_then(|minutes| minutes.checked_mul(60))
+                .filter(|duration| (1..=MAX_MUTE).contains(duration));
+            let Some(duration) = duration else {
+                return Some(Err(MUTE_USAGE));
+            };
+            ModerationAction::Mute { duration }
+        }
+        ("/ban", Some(minutes)) => {
+            let until = if minutes == "forever" {
+                None
+            } else {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|time| time.as_secs() as i64)
+                    .unwrap_or(0);
+                let until = minutes
+                    .parse::<i64>()
+                    .ok()
+                    .filter(|minutes| *minutes > 0)
+                    .and_then(|minutes| minutes.checked_mul(60))
+                    .and_then(|seconds| now.checked_add(seconds));
+                let Some(until) = until else {
+                    return Some(Err(MODERATION_USAGE));
+                };
+                Some(until)
+            };
+            ModerationAction::Ban {
+                reason: words.collect::<Vec<_>>().join(" "),
+                until,
+            }
+        }
+        _ => return Some(Err(MODERATION_USAGE)),
+    };
+    Some(Ok((username.to_string(), action)))
+}
+
+fn request_error_text(err: &client::RequestError) -> &'static str {
+    match err {
+        client::RequestError::Timeout => "The server didn't answer, try again.",
+        client::RequestError::Disconnected => "Connection lost, try again.",
+    }
+}
+
+fn outgoing_conversation(msg: &UserMsg) -> String {
+    match &msg.to {
+        Some(to) => format!("@{}", to),
+        None => msg.room.clone(),
+    }
+}
+
+// Whether a msg sent back by the server is the outgoing one
+fn is_echo(outgoing: &Outgoing, msg: &ServerMsg) -> bool {
+    if outgoing.msg.to != msg.to || (outgoing.msg.to.is_none() && outgoing.msg.room != msg.room) {
+        return false;
+    }
+    match (&outgoing.msg.data, &msg.data) {
+        (MsgDataType::Text(a), MsgDataType::Text(b)) => a == b,
+        (MsgDataType::Image(_), MsgDataType::Image(_)) => true,
+        _ => false,
+    }
 }
 
 impl RustyChat {
@@ -61,6 +256,173 @@ impl RustyChat {
         self.username.clear();
         self.password.clear();
         self.error_msg.clear();
+        self.username_error.clear();
+        self.password_error.clear();
+    }
+
+    fn show_toast(&mut self, text: String) -> Command<Messages> {
+        self.toast = text;
+        self.toast_id += 1;
+        let id = self.toast_id;
+        Command::perform(tokio::time::sleep(TOAST_TIME), move |_| {
+            Messages::ToastExpired(id)
+        })
+    }
+
+    // Forget everything about the logged in user, the stored session is kept
+    fn reset_session(&mut self) {
+        self.clear();
+        self.token.clear();
+        self.role = Role::Guest;
+        self.remember_me = false;
+        self.resuming = false;
+        self.resume_delay = INITIAL_RESUME_DELAY;
+        self.messages.clear();
+        self.outbox.clear();
+        self.notice.clear();
+        self.divider = None;
+        self.room = String::from(DEFAULT_ROOM);
+        self.rooms = BTreeMap::from([(String::from(DEFAULT_ROOM), Room::default())]);
+    }
+
+    // Send the stored token to login again, nothing when there is none or no connection
+    fn resume(&mut self) -> Command<Messages> {
+        let Some(sender) = &self.sender else {
+            return Command::none();
+        };
+        if self.token.is_empty() {
+            return Command::none();
+        }
+        let last_seen_id = self
+            .messages
+            .iter()
+            .map(|msg| msg.id)
+            .chain(self.rooms.values().map(|room| room.last_read_id))
+            .max()
+            .filter(|id| *id > 0);
+        let msg = RequestKind::Resume(ResumeMsg {
+            token: self.token.clone(),
+            last_seen_id,
+        });
+        self.resuming = true;
+        self.resume_attempt += 1;
+        Command::perform(client::request(sender.clone(), msg), Messages::Response)
+    }
+
+    // The session is kept and resumed again after a while, only a refused token ends it
+    fn retry_resume(&mut self, reason: &str) -> Command<Messages> {
+        let delay = self.resume_delay;
+        self.resume_delay = (delay * 2).min(MAX_RESUME_DELAY);
+        self.error_msg = format!("{} Retrying in {} seconds...", reason, delay.as_secs());
+        let attempt = self.resume_attempt;
+        Command::perform(tokio::time::sleep(delay), move |_| {
+            Messages::RetryResume(attempt)
+        })
+    }
+
+    // Switch the subscription to the server, logging in with its stored session if any
+    fn connect_to(&mut self, profile: ServerProfile) {
+        self.reset_session();
+        self.sender = None;
+        self.disconected = true;
+
+        if !self.profiles.servers.contains(&profile) {
+            self.profiles.servers.push(profile.clone());
+        }
+        self.profiles.last = Some(profile.clone());
+        let _ = profiles::save(&self.profiles);
+
+        self.view = match session::load(&profile.address()) {
+            Some(session) => {
+                self.username = session.username;
+                self.token = session.token;
+                self.remember_me = true;
+                Views::Chat
+            }
+            None => Views::LoginForm,
+        };
+        self.server = Some(profile);
+    }
+
+    // A msg for the current room, sent as a direct msg in "@username" rooms
+    fn new_msg(&self, data: MsgDataType) -> UserMsg {
+        UserMsg {
+            room: self.room.clone(),
+            to: self.room.strip_prefix('@').map(String::from),
+            data,
+            token: self.token.clone(),
+        }
+    }
+
+    // Report the newest msg of the current room as read. The server knows of msgs that were
+    // never loaded, its newest id clears those too
+    fn mark_room_read(&mut self) {
+        let loaded = self
+            .messages
+            .iter()
+            .filter(|msg| conversation(msg, &self.username) == self.room)
+            .map(|msg| msg.id)
+            .max()
+            .unwrap_or(0);
+        let room = self.rooms.entry(self.room.clone()).or_default();
+        let newest = loaded.max(room.newest_id);
+        room.unread = 0;
+        if newest <= room.last_read_id {
+            return;
+        }
+        room.last_read_id = newest;
+
+        if let Some(sender) = &mut self.sender {
+            let msg = MsgType::ReadMarker(ReadMarkerMsg {
+                token: self.token.clone(),
+                room: self.room.clone(),
+                last_read_id: newest,
+            });
+            let _ = sender.start_send(client::Input::MsgType(msg));
+        }
+    }
+
+    fn push_outgoing(&mut self, msg: UserMsg, image: Option<PathBuf>) -> Command<Messages> {
+        self.outbox.push(Outgoing {
+            id: self.next_outgoing_id,
+            msg,
+            image,
+            delivery: Delivery::Queued,
+        });
+        self.next_outgoing_id += 1;
+        self.flush_outbox()
+    }
+
+    // Send the queued msgs in order, only once the session is logged in again
+    fn flush_outbox(&mut self) -> Command<Messages> {
+        if self.disconected || self.resuming || self.token.is_empty() {
+            return Command::none();
+        }
+        let Some(sender) = &self.sender else {
+            return Command::none();
+        };
+        let mut commands = Vec::new();
+        for outgoing in self
+            .outbox
+            .iter_mut()
+            .filter(|outgoing| outgoing.delivery == Delivery::Queued)
+        {
+            let mut msg = outgoing.msg.clone();
+            msg.token = self.token.clone();
+            let id = outgoing.id;
+            commands.push(match &outgoing.image {
+                Some(path) => Command::perform(
+                    client::send_image(sender.clone(), path.clone(), msg),
+                    move |reply| Messages::Sent(id, reply),
+                ),
+                None => Command::perform(
+                    client::request(sender.clone(), RequestKind::MsgOut(msg)),
+                    move |reply| Messages::Sent(id, reply),
+                ),
+            });
+            outgoing.delivery = Delivery::Sending;
+        }
+        Command::batch(commands)
     }
 }
 
@@ -71,25 +433,59 @@ impl Application for RustyChat {
     type Flags = ();
 
     fn new(_flags: Self::Flags) -> (Self, iced::Command<Self::Message>) {
-        (
-            Self {
-                messages: Vec::new(),
-                new_message_input: String::from(""),
-                sender: None,
-                disconected: true,
-                loading: false,
-                view: Views::LoginForm,
-                username: String::from(""),
-                password: String::from(""),
-                error_msg: String::from(""),
-                token: String::from(""),
-            },
-            Command::none(),
-        )
+        let mut app = Self {
+            messages: Vec::new(),
+            new_message_input: String::from(""),
+            sender: None,
+            disconected: true,
+            loading: false,
+            view: Views::Connect,
+            username: String::from(""),
+            password: String::from(""),
+            error_msg: String::from(""),
+            username_error: String::from(""),
+            password_error: String::from(""),
+            toast: String::from(""),
+            toast_id: 0,
+            token: String::from(""),
+            room: String::from(DEFAULT_ROOM),
+            rooms: BTreeMap::from([(String::from(DEFAULT_ROOM), Room::default())]),
+            divider: None,
+            at_bottom: true,
+            notice: String::from(""),
+            resuming: false,
+            resume_delay: INITIAL_RESUME_DELAY,
+            resume_attempt: 0,
+            outbox: Vec::new(),
+            next_outgoing_id: 0,
+            remember_me: false,
+            server: None,
+            profiles: profiles::load(),
+            host: String::from(profiles::DEFAULT_HOST),
+            port: String::from(profiles::DEFAULT_PORT),
+            role: Role::Guest,
+            shutdown_notice: String::from(""),
+            latency: None,
+        };
+
+        // Go straight to the last server when its session was remembered, the stored
+        // token is used to resume it once connected
+        app.view = Views::Connect;
+        if let Some(last) = app.profiles.last.clone() {
+            app.host = last.host.clone();
+            app.port = last.port.to_string();
+            if session::load(&last.address()).is_some() {
+                app.connect_to(last);
+            }
+        }
+        (app, Command::none())
     }
 
     fn subscription(&self) -> iced::Subscription<Self::Message> {
-        client::connect().map(Messages::Subscription)
+        match &self.server {
+            Some(server) => client::connect(server.address()).map(Messages::Subscription),
+            None => iced::Subscription::none(),
+        }
     }
 
     fn update(&mut self, message: Self::Message) -> iced::Command<Self::Message> {
@@ -97,45 +493,167 @@ impl Application for RustyChat {
             Messages::Subscription(event) => match event {
                 client::Event::FailConnection => {
                     self.disconected = true;
+                    self.latency = None;
+                    // Maybe the server never got them
+                    for outgoing in self.outbox.iter_mut() {
+                        if outgoing.delivery == Delivery::Sending {
+                            outgoing.delivery = Delivery::Failed;
+                        }
+                    }
                     Command::none()
                 }
                 client::Event::Connected(sender) => {
                     self.sender = Some(sender);
                     self.disconected = false;
+                    self.shutdown_notice.clear();
+                    // Log in again with the token of the lost connection
+                    self.resume()
+                }
+                client::Event::Latency(latency) => {
+                    self.latency = Some(latency);
                     Command::none()
                 }
                 client::Event::MsgRecived(msg) => {
+                    let room = conversation(&msg, &self.username);
+                    if msg.username == self.username {
+                        if let Some(index) = self.outbox.iter().position(|outgoing| {
+                            outgoing.delivery != Delivery::Queued && is_echo(outgoing, &msg)
+                        }) {
+                            self.outbox.remove(index);
+                        }
+                    }
+                    let follow = room == self.room
+                        && (self.at_bottom || msg.username == self.username);
+                    // The server only sends the counts on login and when a read marker
+                    // moves. Msgs up to the newest id it reported are counted already
+                    let entry = self.rooms.entry(room).or_default();
+                    if !follow && msg.username != self.username && msg.id > entry.newest_id {
+                        entry.unread += 1;
+                    }
+                    entry.newest_id = entry.newest_id.max(msg.id);
                     self.messages.push(msg);
+                    if !follow {
+                        return Command::none();
+                    }
+                    self.mark_room_read();
                     scrollable::snap_to(
                         MESSAGE_LOG.clone(),
                         scrollable::RelativeOffset::END,
                     )
-                    // Command::none()
                 }
                 client::Event::ServerRes(res) => {
+                    let mut command = Command::none();
                     match res {
+                        shared_utils::ServerRes::Error(error)
+                            if error.code == ErrorCode::AuthExpired
+                                || (self.resuming && error.code == ErrorCode::Banned) =>
+                        {
+                            // The stored token is no good anymore, the user has to login again
+                            if let Some(server) = &self.server {
+                                session::clear(&server.address());
+                            }
+                            let username = self.username.clone();
+                            self.reset_session();
+                            self.username = username;
+                            self.error_msg = error.text().to_string();
+                            self.view = Views::LoginForm;
+                        }
+                        shared_utils::ServerRes::Error(error) if self.resuming => {
+                            // The stored token is still good, e.g. once the maintenance is over
+                            command = self.retry_resume(error.text());
+                        }
                         shared_utils::ServerRes::Error(error) => {
-                            self.username.clear();
-                            self.password.clear();
-
-                            self.error_msg = error;
+                            match error.code {
+                                ErrorCode::RateLimited => {
+                                    command = self.show_toast(error.text().to_string());
+                                }
+                                ErrorCode::UsernameTaken => {
+                                    self.username_error = error.text().to_string();
+                                }
+                                ErrorCode::InvalidCredentials => {
+                                    self.password.clear();
+                                    self.password_error = error.text().to_string();
+                                }
+                                _ => {
+                                    self.error_msg = error.text().to_string();
+                                }
+                            }
                         }
                         shared_utils::ServerRes::UserToken(msg) => {
                             self.clear();
+                            self.resuming = false;
+                            self.resume_delay = INITIAL_RESUME_DELAY;
+                            if let (true, Some(server)) = (self.remember_me, &self.server) {
+                                let _ = session::save(&session::Session {
+                                    server: server.address(),
+                                    username: msg.username.clone(),
+                                    token: msg.token.clone(),
+                                    expires_at: msg.expires_at,
+                                });
+                            }
 
                             self.token = msg.token;
                             self.username = msg.username;
+                            self.role = msg.role;
 
                             self.view = Views::Chat;
+                            command = self.flush_outbox();
+                        }
+                        shared_utils::ServerRes::Notice(notice) => {
+                            self.notice = notice;
+                        }
+                        shared_utils::ServerRes::Kicked(reason) => {
+                            // Without the token the session isn't resumed on the reconnect,
+                            // the user has to login again
+                            let username = self.username.clone();
+                            self.reset_session();
+                            self.username = username;
+                            self.error_msg = reason;
+                            self.view = Views::LoginForm;
+                        }
+                        shared_utils::ServerRes::ServerShutdown(shutdown) => {
+                            // The subscription waits before reconnecting
+                            self.shutdown_notice = format!(
+                                "{} Reconnecting in {} seconds...",
+                                shutdown.reason, shutdown.reconnect_after
+                            );
+                        }
+                        shared_utils::ServerRes::MsgDeleted(id) => {
+                            self.messages.retain(|msg| msg.id != id);
                         }
                         shared_utils::ServerRes::UserCreated => {
                             self.clear();
 
                             self.view = Views::LoginForm;
                         }
+                        shared_utils::ServerRes::UnreadCounts(counts) => {
+                            // Rooms missing from the list have nothing unread
+                            for room in self.rooms.values_mut() {
+                                room.unread = 0;
+                            }
+                            for count in counts {
+                                let room = self.rooms.entry(count.room.clone()).or_default();
+                                room.unread = count.count;
+                                room.last_read_id = room.last_read_id.max(count.last_read_id);
+                                room.newest_id = room.newest_id.max(count.newest_id);
+                                if count.room == self.room && count.count > 0 && self.divider.is_none() {
+                                    self.divider = Some(count.last_read_id);
+                                }
+                            }
+                            if self.at_bottom {
+                                self.mark_room_read();
+                            }
+                        }
+                        shared_utils::ServerRes::OfflineSummary(summary) => {
+                            self.notice = format!(
+                                "While you were away: {} direct messages, {} mentions",
+                                summary.direct, summary.mentions
+                            );
+                        }
+                        shared_utils::ServerRes::Ack => {}
                     }
                     self.loading = false;
-                    Command::none()
+                    command
                 }
             },
             Messages::NewMessageInput(input) => {
@@ -146,33 +664,51 @@ impl Application for RustyChat {
                 if self.new_message_input.is_empty() {
                     return Command::none();
                 }
-                let a = &self.new_message_input;
-                if let Some(sender) = &mut self.sender {
-                    let msg = UserMsg {
-                        data: MsgDataType::Text(a.to_string()),
-                        username: self.username.clone(),
-                        token: self.token.clone(),
-                    };
-                    sender
-                        .start_send(client::Input::MsgType(MsgType::MsgOut(msg.clone())))
-                        .unwrap();
-                    self.messages.push(ServerMsg {
-                        username: msg.username,
-                        data: msg.data,
-                    });
+                let mut command = Command::none();
+                let mut text = self.new_message_input.clone();
+                if let Some(moderation) = parse_moderation(&text) {
+                    match moderation {
+                        Ok((username, action)) => {
+                            if let Some(sender) = &mut self.sender {
+                                let msg = MsgType::Moderate(ModerationMsg {
+                                    token: self.token.clone(),
+                                    username,
+                                    action,
+                                });
+                                let _ = sender.start_send(client::Input::MsgType(msg));
+                            }
+                            self.error_msg.clear();
+                        }
+                        Err(usage) => self.error_msg = usage.to_string(),
+                    }
+                    self.new_message_input.clear();
+                    return command;
+                }
+                // "/msg username text" opens the direct msg room of that user
+                if let Some(rest) = text.strip_prefix("/msg ") {
+                    if let Some((to, rest)) = rest.trim_start().split_once(' ') {
+                        let room = format!("@{}", to);
+                        text = rest.to_string();
+                        self.rooms.entry(room.clone()).or_default();
+                        command = self.update(Messages::SelectRoom(room));
+                    }
                 }
+                // Pending until the server sends the msg back once stored
+                let msg = self.new_msg(MsgDataType::Text(text));
                 self.new_message_input.clear();
-                Command::none()
+                Command::batch([command, self.push_outgoing(msg, None)])
             }
             Messages::UsernameInput(username) => {
                 if username.len() <= 30 {
                     self.username = username;
+                    self.username_error.clear();
                 }
                 Command::none()
             }
             Messages::PasswordInput(password) => {
                 if password.len() <= 80 {
                     self.password = password;
+                    self.password_error.clear();
                 }
                 Command::none()
             }
@@ -183,14 +719,19 @@ impl Application for RustyChat {
                 }
                 self.loading = true;
 
-                if let Some(sender) = &mut self.sender {
-                    let msg = MsgType::Signup(LoginMsg {
-                        username: self.username.clone(),
-                        password: self.password.clone(),
-                    });
-                    sender.start_send(client::Input::MsgType(msg)).unwrap();
+                match &self.sender {
+                    Some(sender) => {
+                        let msg = RequestKind::Signup(LoginMsg {
+                            username: self.username.clone(),
+                            password: self.password.clone(),
+                        });
+                        Command::perform(
+                            client::request(sender.clone(), msg),
+                            Messages::Response,
+                        )
+                    }
+                    None => Command::none(),
                 }
-                Command::none()
             }
             Messages::SubmitLoginForm => {
                 if self.username.is_empty() || self.password.is_empty() {
@@ -199,14 +740,19 @@ impl Application for RustyChat {
                 }
                 self.loading = true;
 
-                if let Some(sender) = &mut self.sender {
-                    let msg = MsgType::Login(LoginMsg {
-                        username: self.username.clone(),
-                        password: self.password.clone(),
-                    });
-                    sender.start_send(client::Input::MsgType(msg)).unwrap();
+                match &self.sender {
+                    Some(sender) => {
+                        let msg = RequestKind::Login(LoginMsg {
+                            username: self.username.clone(),
+                            password: self.password.clone(),
+                        });
+                        Command::perform(
+                            client::request(sender.clone(), msg),
+                            Messages::Response,
+                        )
+                    }
+                    None => Command::none(),
                 }
-                Command::none()
             }
             Messages::SubmitImg => {
                 let path = FileDialog::new()
@@ -214,11 +760,109 @@ impl Application for RustyChat {
                     .add_filter("Image", &["jpg", "jpeg", "png"])
                     .show_open_single_file()
                     .unwrap();
-                if let Some(path) = path {
-                    if let Some(sender) = &mut self.sender {
-                        sender.start_send(client::Input::ReadImgFile(path, self.username.clone(), self.token.clone())).unwrap();
+                match path {
+                    Some(path) => {
+                        let msg = self.new_msg(MsgDataType::Image(Vec::new()));
+                        self.push_outgoing(msg, Some(path))
                     }
+                    None => Command::none(),
                 }
+            }
+            Messages::SelectRoom(room) => {
+                self.room = room;
+                self.divider = self
+                    .rooms
+                    .get(&self.room)
+                    .filter(|room| room.unread > 0)
+                    .map(|room| room.last_read_id);
+                self.at_bottom = true;
+                self.mark_room_read();
+                scrollable::snap_to(MESSAGE_LOG.clone(), scrollable::RelativeOffset::END)
+            }
+            Messages::MessageLogScrolled(offset) => {
+                self.at_bottom = offset.y >= 0.99;
+                if self.at_bottom {
+                    self.mark_room_read();
+                }
+                Command::none()
+            }
+            Messages::RetryOutgoing(id) => {
+                if let Some(outgoing) = self.outbox.iter_mut().find(|outgoing| outgoing.id == id) {
+                    outgoing.delivery = Delivery::Queued;
+                }
+                self.flush_outbox()
+            }
+            Messages::CancelOutgoing(id) => {
+                self.outbox.retain(|outgoing| outgoing.id != id);
+                Command::none()
+            }
+            Messages::RememberMeToggled(remember_me) => {
+                self.remember_me = remember_me;
+                Command::none()
+            }
+            Messages::Logout => {
+                if let Some(sender) = &mut self.sender {
+                    let _ = sender.start_send(client::Input::MsgType(MsgType::Logout));
+                }
+                if let Some(server) = &self.server {
+                    session::clear(&server.address());
+                }
+                self.reset_session();
+                self.view = Views::LoginForm;
+                Command::none()
+            }
+            Messages::HostInput(host) => {
+                self.host = host;
+                Command::none()
+            }
+            Messages::PortInput(port) => {
+                if port.len() <= 5 && port.chars().all(|c| c.is_ascii_digit()) {
+                    self.port = port;
+                }
+                Command::none()
+            }
+            Messages::SubmitConnectForm => {
+                let host = self.host.trim().to_string();
+                match self.port.parse::<u16>() {
+                    Ok(port) if !host.is_empty() => {
+                        self.connect_to(ServerProfile { host, port });
+                    }
+                    _ => {
+                        self.error_msg = "Enter a valid host and port".to_string();
+                    }
+                }
+                Command::none()
+            }
+            Messages::SelectServer(profile) => {
+                self.connect_to(profile);
+                Command::none()
+            }
+            Messages::RemoveServer(profile) => {
+                self.profiles.servers.retain(|server| *server != profile);
+                if self.profiles.last.as_ref() == Some(&profile) {
+                    self.profiles.last = None;
+                }
+                session::clear(&profile.address());
+                let _ = profiles::save(&self.profiles);
+                Command::none()
+            }
+            Messages::DeleteMsg(id) => {
+                if let Some(sender) = &mut self.sender {
+                    let msg = MsgType::Delete(DeleteMsg {
+                        token: self.token.clone(),
+                        id,
+                    });
+                    let _ = sender.start_send(client::Input::MsgType(msg));
+                }
+                Command::none()
+            }
+            Messages::ChangeServer => {
+                // Dropping the subscription closes the connection, the stored session is kept
+                self.reset_session();
+                self.server = None;
+                self.sender = None;
+                self.disconected = true;
+                self.view = Views::Connect;
                 Command::none()
             }
             Messages::ChangeView(view) => {
@@ -226,12 +870,102 @@ impl Application for RustyChat {
                 self.view = view;
                 Command::none()
             }
+            Messages::ToastExpired(id) => {
+                if id == self.toast_id {
+                    self.toast.clear();
+                }
+                Command::none()
+            }
+            Messages::Response(Ok(res)) => {
+                self.update(Messages::Subscription(client::Event::ServerRes(res)))
+            }
+            Messages::Response(Err(err)) if self.resuming => {
+                self.retry_resume(request_error_text(&err))
+            }
+            Messages::Response(Err(err)) => {
+                self.loading = false;
+                self.error_msg = request_error_text(&err).to_string();
+                Command::none()
+            }
+            Messages::RetryResume(attempt) => {
+                // A reconnect resumes the session by itself
+                if attempt != self.resume_attempt || !self.resuming || self.disconected {
+                    return Command::none();
+                }
+                self.resume()
+            }
+            Messages::Sent(id, reply) => {
+                let refused = match reply {
+                    // The msg comes back from the server like any other
+                    Ok(shared_utils::ServerRes::Ack) => return Command::none(),
+                    Ok(shared_utils::ServerRes::Error(error)) => Some(error),
+                    Ok(_) | Err(_) => None,
+                };
+                // The user can retry or cancel it
+                if let Some(outgoing) = self.outbox.iter_mut().find(|outgoing| outgoing.id == id) {
+                    outgoing.delivery = Delivery::Failed;
+                }
+                match refused {
+                    Some(error) => self.update(Messages::Subscription(client::Event::ServerRes(
+                        shared_utils::ServerRes::Error(error),
+                    ))),
+                    None => Command::none(),
+                }
+            }
         }
     }
 
     fn view(&self) -> iced::Element<'_, Self::Message> {
-        if !self.disconected {
+        if let Views::Connect = self.view {
+            let input_host = text_input("Host", &self.host)
+                .on_input(Messages::HostInput)
+                .on_submit(Messages::SubmitConnectForm)
+                .width(250);
+            let input_port = text_input("Port", &self.port)
+                .on_input(Messages::PortInput)
+                .on_submit(Messages::SubmitConnectForm)
+                .width(88);
+            let submit_button = button("Connect").on_press(Messages::SubmitConnectForm);
+            let servers = Column::with_children(
+                self.profiles
+                    .servers
+                    .iter()
+                    .map(|server| {
+                        row![
+                            text(server.address()).width(250),
+                            button("Connect").on_press(Messages::SelectServer(server.clone())),
+                            button("Remove").on_press(Messages::RemoveServer(server.clone())),
+                        ]
+                        .spacing(12)
+                        .into()
+                    })
+                    .collect(),
+            )
+            .spacing(6);
+
+            let error_text = text(&self.error_msg).style(color!(0xFB0000));
+            return container(
+                column![
+                    text("Server").size(28),
+                    row![input_host, input_port].spacing(12),
+                    submit_button,
+                    error_text,
+                    text("Saved servers").size(20),
+                    servers
+                ]
+                .spacing(12),
+            )
+            .width(Length::Fill)
+            .height(Length::Fill)
+            .center_x()
+            .center_y()
+            .into();
+        }
+
+        // A logged in user keeps the chat while reconnecting
+        if !self.disconected || !self.token.is_empty() {
             match self.view {
+                Views::Connect => {}
                 Views::SignupForm => {
                     let mut input_name = text_input("Enter username", &self.username).width(350);
                     let mut input_password = text_input("Enter password", &self.password)
@@ -257,9 +991,12 @@ impl Application for RustyChat {
                         column![
                             text("Signup").size(28),
                             input_name,
+                            text(&self.username_error).style(color!(0xFB0000)),
                             input_password,
+                            text(&self.password_error).style(color!(0xFB0000)),
                             row_button,
-                            error_text
+                            error_text,
+                            text(&self.toast).style(color!(0xFFB000))
                         ]
                         .spacing(12),
                     )
@@ -287,16 +1024,26 @@ impl Application for RustyChat {
                         submit_button = submit_button.on_press(Messages::SubmitLoginForm);
                         swap_button = swap_button.on_press(Messages::ChangeView(Views::SignupForm));
                     }
-                    let row_button = row![submit_button, swap_button].spacing(16);
+                    let change_server = button("Change server").on_press(Messages::ChangeServer);
+                    let row_button = row![submit_button, swap_button, change_server].spacing(16);
+                    let remember_me = checkbox(
+                        "Remember me",
+                        self.remember_me,
+                        Messages::RememberMeToggled,
+                    );
 
                     let error_text = text(&self.error_msg).style(color!(0xFB0000));
                     return container(
                         column![
                             text("Login").size(28),
                             input_name,
+                            text(&self.username_error).style(color!(0xFB0000)),
                             input_password,
+                            text(&self.password_error).style(color!(0xFB0000)),
+                            remember_me,
                             row_button,
-                            error_text
+                            error_text,
+                            text(&self.toast).style(color!(0xFFB000))
                         ]
                         .spacing(12),
                     )
@@ -307,66 +1054,161 @@ impl Application for RustyChat {
                     .into();
                 }
                 Views::Chat => {
-                    let input = text_input("", &self.new_message_input)
-                        .on_input(Messages::NewMessageInput)
-                        .on_submit(Messages::SubmitNewMessage);
+                    let mut input = text_input("", &self.new_message_input);
                     // let submit = button("Send").on_press(Messages::SubmitNewMessage);
-                    let submit = Button::new(
+                    let mut submit = Button::new(
                         Text::new(Icon::ArrowUpRight.to_string())
                             .width(Length::Shrink)
                             .height(Length::Shrink)
                             .font(ICON_FONT),
+                    );
+                    if self.role.can(Permission::SendMessage) {
+                        input = input
+                            .on_input(Messages::NewMessageInput)
+                            .on_submit(Messages::SubmitNewMessage);
+                        submit = submit.on_press(Messages::SubmitNewMessage);
+                    }
+                    let mut input_row = row![input, submit].spacing(6);
+                    if self.role.can(Permission::UploadImage) {
+                        let submit_img = Button::new(
+                            Text::new(Icon::ImageAlt.to_string())
+                                .width(Length::Shrink)
+                                .height(Length::Shrink)
+                                .font(ICON_FONT),
+                        )
+                        .on_press(Messages::SubmitImg);
+                        input_row = input_row.push(submit_img);
+                    }
+                    let mut log: Vec<Element<'_, Self::Message>> = Vec::new();
+                    let mut divider = self.divider;
+                    for msg in self
+                        .messages
+                        .iter()
+                        .filter(|msg| conversation(msg, &self.username) == self.room)
+                    {
+                        if let Some(last_read_id) = divider {
+                            if msg.id > last_read_id && msg.username != self.username {
+                                log.push(
+                                    row![
+                                        horizontal_rule(1),
+                                        text("New messages").style(color!(0xFB0000)),
+                                        horizontal_rule(1)
+                                    ]
+                                    .spacing(6)
+                                    .into(),
+                                );
+                                divider = None;
+                            }
+                        }
+                        let color = if msg.username == self.username {
+                            0xff5c00
+                        } else {
+                            0x005c00
+                        };
+                        let mut entry = match &msg.data {
+                            MsgDataType::Text(msg_text) => row![
+                                text(format!("[{}]", msg.username)).style(color!(color)),
+                                text(msg_text),
+                            ]
+                            .spacing(6),
+                            MsgDataType::Image(buffer) => {
+                                let mem = Handle::from_memory(buffer.clone());
+                                let img = Image::<Handle>::new(mem);
+                                row![column![
+                                    text(format!("[{}]", msg.username)).style(color!(color)),
+                                    Container::new(img).max_height(450)
+                                ]
+                                .spacing(6)]
+                                .spacing(6)
+                            }
+                        };
+                        if msg.username == self.username
+                            || self.role.can(Permission::DeleteOthersMessages)
+                        {
+                            entry = entry.push(
+                                Button::new(Text::new(Icon::Trash.to_string()).font(ICON_FONT))
+                                    .on_press(Messages::DeleteMsg(msg.id)),
+                            );
+                        }
+                        log.push(entry.into());
+                    }
+                    for outgoing in self
+                        .outbox
+                        .iter()
+                        .filter(|outgoing| outgoing_conversation(&outgoing.msg) == self.room)
+                    {
+                        let content = match (&outgoing.msg.data, &outgoing.image) {
+                            (MsgDataType::Text(msg_text), _) => msg_text.clone(),
+                            (_, Some(path)) => format!(
+                                "Image: {}",
+                                path.file_name().unwrap_or_default().to_string_lossy()
+                            ),
+                            _ => String::from("Image"),
+                        };
+                        let mut pending = row![
+                            text(format!("[{}]", self.username)).style(color!(0x808080)),
+                            text(content).style(color!(0x808080)),
+                        ]
+                        .spacing(6);
+                        pending = match outgoing.delivery {
+                            Delivery::Queued => pending.push(text("(waiting)")),
+                            Delivery::Sending => pending.push(text("(sending...)")),
+                            Delivery::Failed => pending
+                                .push(text("(failed)").style(color!(0xFB0000)))
+                                .push(button("Retry").on_press(Messages::RetryOutgoing(outgoing.id)))
+                                .push(button("Cancel").on_press(Messages::CancelOutgoing(outgoing.id))),
+                        };
+                        log.push(pending.into());
+                    }
+                    let room_list = Column::with_children(
+                        self.rooms
+                            .iter()
+                            .map(|(name, room)| {
+                                let mut label = if *name == self.room {
+                                    format!("> #{}", name)
+                                } else {
+                                    format!("#{}", name)
+                                };
+                                if room.unread > 0 {
+                                    label = format!("{} ({})", label, room.unread);
+                                }
+                                button(text(label))
+                                    .width(Length::Fill)
+                                    .on_press(Messages::SelectRoom(name.clone()))
+                                    .into()
+                            })
+                            .collect(),
                     )
-                    .on_press(Messages::SubmitNewMessage);
-                    let submit_img = Button::new(
-                        Text::new(Icon::ImageAlt.to_string())
-                            .width(Length::Shrink)
-                            .height(Length::Shrink)
-                            .font(ICON_FONT),
-                    )
-                    .on_press(Messages::SubmitImg);
+                    .spacing(6)
+                    .width(180)
+                    .push(button("Log out").on_press(Messages::Logout))
+                    .push(button("Change server").on_press(Messages::ChangeServer))
+                    .push(text(match self.latency {
+                        Some(latency) => format!("Ping: {} ms", latency.as_millis()),
+                        None => String::from("Ping: -"),
+                    }));
                     return container(
-                        column![
-                            scrollable(
-                                Column::with_children(
-                                    self.messages
-                                        .iter()
-                                        .map(|msg| {
-                                            let color = if msg.username == self.username {
-                                                0xff5c00
-                                            } else {
-                                                0x005c00
-                                            };
-                                            match &msg.data {
-                                                MsgDataType::Text(msg_text) => {
-                                                    return row![
-                                                        text(format!("[{}]", msg.username))
-                                                            .style(color!(color)),
-                                                        text(msg_text),
-                                                    ]
-                                                    .spacing(6)
-                                                }
-                                                MsgDataType::Image(buffer) => {
-                                                    let mem = Handle::from_memory(buffer.clone());
-                                                    let img = Image::<Handle>::new(mem);
-                                                    return row![column![
-                                                        text(format!("[{}]", msg.username))
-                                                            .style(color!(color)),
-                                                        Container::new(img).max_height(450)
-                                                    ]
-                                                    .spacing(6)];
-                                                }
-                                            }
-                                        })
-                                        .map(Element::from)
-                                        .collect()
-                                )
-                                .spacing(6)
-                            )
-                            .width(Length::Fill)
-                            .height(Length::Fill)
-                            .id(MESSAGE_LOG.clone()),
-                            row![input, submit, submit_img].spacing(6)
+                        row![
+                            room_list,
+                            column![
+                                text(if self.disconected && !self.shutdown_notice.is_empty() {
+                                    self.shutdown_notice.as_str()
+                                } else if self.disconected {
+                                    "Reconnecting..."
+                                } else {
+                                    self.notice.as_str()
+                                })
+                                .style(color!(0x5c5cff)),
+                                scrollable(Column::with_children(log).spacing(6))
+                                    .width(Length::Fill)
+                                    .height(Length::Fill)
+                                    .on_scroll(Messages::MessageLogScrolled)
+                                    .id(MESSAGE_LOG.clone()),
+                                input_row,
+                                text(&self.toast).style(color!(0xFFB000)),
+                                text(&self.error_msg).style(color!(0xFB0000))
+                            ]
+                            .spacing(10)
                         ]
                         .spacing(10)
                         .padding(20),
@@ -377,7 +1219,20 @@ impl Application for RustyChat {
                 }
             }
         }
-        container(text("Disconnected...")).into()
+        let server = self
+            .server
+            .as_ref()
+            .map(|server| server.address())
+            .unwrap_or_default();
+        container(
+            column![
+                text(&self.shutdown_notice),
+                text(format!("Connecting to {}...", server)),
+                button("Change server").on_press(Messages::ChangeServer)
+            ]
+            .spacing(12),
+        )
+        .into()
     }
 
     fn theme(&self) -> Self::Theme {
@@ -0,0 +1,44 @@
+use std::{fs, io};
+
+use serde::{Deserialize, Serialize};
+
+use crate::storage;
+
+pub const DEFAULT_HOST: &str = "127.0.0.1";
+pub const DEFAULT_PORT: &str = "8000";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServerProfile {
+    pub host: String,
+    pub port: u16,
+}
+
+impl ServerProfile {
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+// Saved servers and the one used last
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Profiles {
+    pub last: Option<ServerProfile>,
+    pub servers: Vec<ServerProfile>,
+}
+
+const PROFILES_FILE: &str = "servers.toml";
+
+pub fn load() -> Profiles {
+    storage::config_file(PROFILES_FILE)
+        .and_then(|path| fs::read_to_string(path).ok())
+        .and_then(|content| toml::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+pub fn save(profiles: &Profiles) -> io::Result<()> {
+    let path = storage::config_file(PROFILES_FILE)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No config directory"))?;
+    let content = toml::to_string(profiles)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    storage::write_private(&path, &content)
+}
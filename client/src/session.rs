@@ -0,0 +1,50 @@
+use std::{
+    fs, io,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::storage;
+
+// Login remembered between launches, one for each server
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Session {
+    pub server: String,
+    pub username: String,
+    pub token: String,
+    // Unix time in seconds
+    pub expires_at: i64,
+}
+
+fn session_path(server: &str) -> Option<PathBuf> {
+    let name: String = server
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    storage::config_file(&format!("sessions/{}.toml", name))
+}
+
+// The stored session of the server, unless it's missing, unreadable or expired
+pub fn load(server: &str) -> Option<Session> {
+    let content = fs::read_to_string(session_path(server)?).ok()?;
+    let session: Session = toml::from_str(&content).ok()?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs() as i64;
+    (session.server == server && session.expires_at > now).then_some(session)
+}
+
+// The token is as good as the password, only the user can read the file
+pub fn save(session: &Session) -> io::Result<()> {
+    let path = session_path(&session.server)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No config directory"))?;
+    let content = toml::to_string(session)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    storage::write_private(&path, &content)
+}
+
+pub fn clear(server: &str) {
+    if let Some(path) = session_path(server) {
+        let _ = fs::remove_file(path);
+    }
+}
@@ -0,0 +1,33 @@
+use std::{fs, io, io::Write, path::PathBuf};
+
+// File inside the per-user config directory of the client
+pub fn config_file(name: &str) -> Option<PathBuf> {
+    Some(dirs::config_dir()?.join("rusty-chat").join(name))
+}
+
+// Write a file only the user can read, creating its directory if needed
+pub fn write_private(path: &PathBuf, content: &str) -> io::Result<()> {
+    let mut dir_builder = fs::DirBuilder::new();
+    dir_builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        dir_builder.mode(0o700);
+    }
+    if let Some(dir) = path.parent() {
+        dir_builder.create(dir)?;
+    }
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        // The mode is only used when the file is created
+        if path.exists() {
+            fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        }
+    }
+    options.open(path)?.write_all(content.as_bytes())
+}
@@ -0,0 +1,607 @@
+use crate::{
+    config::ConfigError,
+    handlers::{kicked, notice, now, Shared},
+    migrations::{self, MigrateError},
+    registry::ConnectionId,
+    storage::{self, Storage, User},
+    tokens,
+};
+use bcrypt::{hash, DEFAULT_COST};
+use shared_utils::Role;
+use std::{fmt, path::PathBuf, sync::atomic::Ordering};
+#[cfg(unix)]
+use subtle::ConstantTimeEq;
+#[cfg(unix)]
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+};
+#[cfg(unix)]
+use tracing::{info, warn};
+
+// Where a running server takes admin commands, overridden by ADMIN_SOCKET
+const DEFAULT_SOCKET: &str = "chat-console.sock";
+// Random bytes of a generated admin token
+#[cfg(unix)]
+const TOKEN_SIZE: usize = 16;
+// Longest command the socket reads
+#[cfg(unix)]
+const MAX_REQUEST_SIZE: u64 = 64 * 1024;
+// Longest timed ban, ten years. Longer ones are permanent
+const MAX_BAN_MINUTES: i64 = 10 * 365 * 24 * 60;
+
+pub const USAGE: &str = "Admin commands:
+  list-users
+  create-user <name>
+  delete-user <name>
+  reset-password <name>
+  grant <name> <guest|member|moderator|admin|owner>
+  ban <name> <reason> [minutes]
+  unban <name>
+  sessions
+  purge room <room>
+  purge user <name>
+  stats
+
+Only while the server is running:
+  connections
+  kick-session <connection>
+  announce <text>
+  maintenance <on|off>
+  reload-config
+
+Passwords are read from stdin. The socket takes ADMIN_TOKEN, or the token the server wrote next to it";
+
+pub enum Purge {
+    Room(String),
+    User(String),
+}
+
+pub enum Command {
+    ListUsers,
+    CreateUser {
+        name: String,
+        password: String,
+    },
+    DeleteUser(String),
+    ResetPassword {
+        name: String,
+        password: String,
+    },
+    Grant {
+        name: String,
+        role: Role,
+    },
+    // Without minutes the ban is permanent
+    Ban {
+        name: String,
+        reason: String,
+        minutes: Option<i64>,
+    },
+    Unban(String),
+    Sessions,
+    Purge(Purge),
+    Stats,
+    Connections,
+    KickSession(ConnectionId),
+    Announce(String),
+    // Only admins can login while it's on
+    Maintenance(bool),
+    ReloadConfig,
+}
+
+impl Command {
+    pub fn parse(args: &[String]) -> Option<Command> {
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        Some(match args.as_slice() {
+            ["list-users"] => Command::ListUsers,
+            ["create-user", name, password] => Command::CreateUser {
+                name: name.to_string(),
+                password: password.to_string(),
+            },
+            ["delete-user", name] => Command::DeleteUser(name.to_string()),
+            ["reset-password", name, password] => Command::ResetPassword {
+                name: name.to_string(),
+                password: password.to_string(),
+            },
+            ["grant", name, role] => Command::Grant {
+                name: name.to_string(),
+                role: Role::parse(role)?,
+            },
+            ["ban", name, reason] => Command::Ban {
+                name: name.to_string(),
+                reason: reason.to_string(),
+                minutes: None,
+            },
+            ["ban", name, reason, minutes] => Command::Ban {
+                name: name.to_string(),
+                reason: reason.to_string(),
+                minutes: Some(
+                    minutes
+                        .parse()
+                        .ok()
+                        .filter(|minutes| (1..=MAX_BAN_MINUTES).contains(minutes))?,
+                ),
+            },
+            ["unban", name] => Command::Unban(name.to_string()),
+            ["sessions"] => Command::Sessions,
+            ["purge", "room", room] => Command::Purge(Purge::Room(room.to_string())),
+            ["purge", "user", name] => Command::Purge(Purge::User(name.to_string())),
+            ["stats"] => Command::Stats,
+            ["connections"] => Command::Connections,
+            ["kick-session", id] => Command::KickSession(id.parse().ok()?),
+            ["announce", text] => Command::Announce(text.to_string()),
+            ["maintenance", "on"] => Command::Maintenance(true),
+            ["maintenance", "off"] => Command::Maintenance(false),
+            ["reload-config"] => Command::ReloadConfig,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum AdminError {
+    Database(sqlx::Error),
+    Hash(bcrypt::BcryptError),
+    Migrate(MigrateError),
+    Config(ConfigError),
+    // Talking to the admin socket failed
+    Io(std::io::Error),
+    UnknownUser(String),
+    UnknownConnection(ConnectionId),
+    NameTaken(String),
+    // Only a running server has sessions
+    NotRunning,
+    NotMigrated,
+    // The token file couldn't be read
+    NoToken(std::io::Error),
+    BadToken,
+    // The running server couldn't run the command
+    Refused(String),
+}
+
+impl fmt::Display for AdminError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdminError::Database(err) => write!(f, "database error: {}", err),
+            AdminError::Hash(err) => write!(f, "password hash error: {}", err),
+            AdminError::Migrate(err) => write!(f, "{}", err),
+            AdminError::Config(err) => write!(f, "{}, the old one stays", err),
+            AdminError::Io(err) => write!(f, "admin socket error: {}", err),
+            AdminError::UnknownUser(name) => write!(f, "there is no user named {}", name),
+            AdminError::UnknownConnection(id) => write!(f, "there is no connection {}", id),
+            AdminError::NameTaken(name) => write!(f, "the name {} is taken", name),
+            AdminError::NotRunning => write!(f, "the server isn't running"),
+            AdminError::NotMigrated => write!(
+                f,
+                "the database has pending migrations, run `chat-console migrate up`"
+            ),
+            AdminError::NoToken(err) => write!(f, "couldn't read the admin token: {}", err),
+            AdminError::BadToken => write!(f, "invalid admin token"),
+            AdminError::Refused(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for AdminError {}
+
+impl From<sqlx::Error> for AdminError {
+    fn from(err: sqlx::Error) -> Self {
+        AdminError::Database(err)
+    }
+}
+
+impl From<ConfigError> for AdminError {
+    fn from(err: ConfigError) -> Self {
+        AdminError::Config(err)
+    }
+}
+
+impl From<bcrypt::BcryptError> for AdminError {
+    fn from(err: bcrypt::BcryptError) -> Self {
+        AdminError::Hash(err)
+    }
+}
+
+impl From<MigrateError> for AdminError {
+    fn from(err: MigrateError) -> Self {
+        AdminError::Migrate(err)
+    }
+}
+
+impl From<std::io::Error> for AdminError {
+    fn from(err: std::io::Error) -> Self {
+        AdminError::Io(err)
+    }
+}
+
+// Their password is read from stdin, on the command line ps and the shell history would show it
+pub fn takes_password(command: &str) -> bool {
+    command == "create-user" || command == "reset-password"
+}
+
+pub fn read_password() -> std::io::Result<String> {
+    use std::io::{IsTerminal, Write};
+
+    if std::io::stdin().is_terminal() {
+        eprint!("Password: ");
+        std::io::stderr().flush()?;
+    }
+    let mut password = String::new();
+    std::io::stdin().read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']).to_string();
+    if password.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "the password can't be empty",
+        ));
+    }
+    Ok(password)
+}
+
+pub fn socket_path() -> PathBuf {
+    std::env::var("ADMIN_SOCKET")
+        .unwrap_or_else(|_| DEFAULT_SOCKET.to_string())
+        .into()
+}
+
+// Next to the socket, holds the token the server generated when ADMIN_TOKEN is unset
+#[cfg(unix)]
+fn token_path() -> PathBuf {
+    let mut path = socket_path().into_os_string();
+    path.push(".token");
+    path.into()
+}
+
+// The token every command sent to the socket starts with
+#[cfg(unix)]
+fn client_token() -> Result<String, AdminError> {
+    if let Ok(token) = std::env::var("ADMIN_TOKEN") {
+        return Ok(token);
+    }
+    std::fs::read_to_string(token_path())
+        .map(|token| token.trim().to_string())
+        .map_err(AdminError::NoToken)
+}
+
+// ADMIN_TOKEN, or a new random one written to the token file only the owner can read
+#[cfg(unix)]
+fn server_token() -> std::io::Result<String> {
+    if let Ok(token) = std::env::var("ADMIN_TOKEN") {
+        return Ok(token);
+    }
+    let token = tokens::random_hex(TOKEN_SIZE)?;
+    let path = token_path();
+    let _ = std::fs::remove_file(&path);
+    tokens::write_private(&path, &token)?;
+    Ok(token)
+}
+
+async fn find_user(db: &dyn Storage, name: &str) -> Result<User, AdminError> {
+    db.user_by_name(name)
+        .await?
+        .ok_or_else(|| AdminError::UnknownUser(name.to_string()))
+}
+
+// Runs the command, live is the running server when it came through the admin socket.
+// Returns what is printed
+pub async fn execute(
+    command: &Command,
+    db: &dyn Storage,
+    live: Option<&Shared>,
+) -> Result<String, AdminError> {
+    // Sessions of removed and banned users, and of the ones whose password was reset, are
+    // closed right away
+    let kick = |name: &str, text: String| {
+        if let Some(shared) = live {
+            shared.registry.kick(name, notice(text), &shared.metrics);
+        }
+    };
+    match command {
+        Command::ListUsers => Ok(db
+            .list_users()
+            .await?
+            .iter()
+            .map(|user| format!("{:>5} {:<9} {}", user.id, user.role.as_str(), user.name))
+            .collect::<Vec<_>>()
+            .join("\n")),
+        Command::CreateUser { name, password } => {
+            let hashed = hash(password, DEFAULT_COST)?;
+            if !db.create_user(name, &hashed).await? {
+                return Err(AdminError::NameTaken(name.clone()));
+            }
+            Ok(format!("User {} created", name))
+        }
+        Command::DeleteUser(name) => {
+            let user = find_user(db, name).await?;
+            db.delete_user(user.id).await?;
+            kick(name, "Your account was deleted.".to_string());
+            Ok(format!("User {} deleted", name))
+        }
+        Command::ResetPassword { name, password } => {
+            let user = find_user(db, name).await?;
+            db.set_password(user.id, &hash(password, DEFAULT_COST)?)
+                .await?;
+            kick(name, "Your password was reset, login again.".to_string());
+            Ok(format!("Password of {} reset", name))
+        }
+        Command::Grant { name, role } => {
+            let user = find_user(db, name).await?;
+            db.set_role(user.id, *role).await?;
+            Ok(format!("{} is now {}", name, role.as_str()))
+        }
+        Command::Ban {
+            name,
+            reason,
+            minutes,
+        } => {
+            let user = find_user(db, name).await?;
+            let until = minutes.map(|minutes| now() + minutes * 60);
+            db.ban_user(user.id, reason, until, None).await?;
+            kick(name, format!("You were banned: {}", reason));
+            Ok(match minutes {
+                Some(minutes) => format!("{} banned for {} minutes", name, minutes),
+                None => format!("{} banned", name),
+            })
+        }
+        Command::Unban(name) => {
+            let user = find_user(db, name).await?;
+            Ok(if db.unban_user(user.id).await? {
+                format!("{} unbanned", name)
+            } else {
+                format!("{} wasn't banned", name)
+            })
+        }
+        Command::Sessions => Ok(live
+            .ok_or(AdminError::NotRunning)?
+            .registry
+            .connections()
+            .iter()
+            .filter_map(|connection| {
+                let name = connection.username.as_ref()?;
+                Some(format!(
+                    "{:>5} {} {}",
+                    connection.id,
+                    name,
+                    connection.rooms.join(",")
+                ))
+            })
+            .collect::<Vec<_>>()
+            .join("\n")),
+        Command::Purge(Purge::Room(room)) => {
+            let deleted = db.delete_room_messages(room).await?;
+            Ok(format!("{} msgs deleted", deleted))
+        }
+        Command::Purge(Purge::User(name)) => {
+            let user = find_user(db, name).await?;
+            let deleted = db.delete_user_messages(user.id).await?;
+            Ok(format!("{} msgs deleted", deleted))
+        }
+        Command::Stats => {
+            let stats = db.stats(now()).await?;
+            let mut out = format!(
+                "users: {}\nmsgs: {}\nactive bans: {}\nactive mutes: {}",
+                stats.users, stats.messages, stats.active_bans, stats.active_mutes
+            );
+            if let Some(shared) = live {
+                let (connections, users) = shared.registry.counts();
+                out.push_str(&format!(
+                    "\nconnections: {}\nlogged in users: {}",
+                    connections, users
+                ));
+            }
+            Ok(out)
+        }
+        Command::Connections => Ok(live
+            .ok_or(AdminError::NotRunning)?
+            .registry
+            .connections()
+            .iter()
+            .map(|connection| {
+                format!(
+                    "{:>5} {:<21} {:<16} {:>7}s {}",
+                    connection.id,
+                    connection.addr,
+                    connection.username.as_deref().unwrap_or("-"),
+                    connection.connected_for.as_secs(),
+                    connection.rooms.join(",")
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")),
+        Command::KickSession(id) => {
+            let shared = live.ok_or(AdminError::NotRunning)?;
+            let text = "You were disconnected by an admin.".to_string();
+            if let Some(user_id) = shared.registry.user_id(*id) {
+                shared.registry.cool_down(user_id);
+            }
+            if !shared.registry.close(*id, kicked(text), &shared.metrics) {
+                return Err(AdminError::UnknownConnection(*id));
+            }
+            Ok(format!("Connection {} closed", id))
+        }
+        Command::Announce(text) => {
+            let shared = live.ok_or(AdminError::NotRunning)?;
+            shared
+                .registry
+                .to_logged(notice(text.clone()), &shared.metrics);
+            Ok("Announcement sent".to_string())
+        }
+        Command::Maintenance(on) => {
+            let shared = live.ok_or(AdminError::NotRunning)?;
+            shared.maintenance.store(*on, Ordering::Relaxed);
+            if *on {
+                // Logged in users stay, they are only told
+                let text = "The server is under maintenance, new logins are closed.".to_string();
+                shared.registry.to_logged(notice(text), &shared.metrics);
+                Ok("Maintenance mode on".to_string())
+            } else {
+                Ok("Maintenance mode off".to_string())
+            }
+        }
+        Command::ReloadConfig => {
+            let shared = live.ok_or(AdminError::NotRunning)?;
+            shared.settings.reload(&shared.limiter)?;
+            Ok("Config reloaded".to_string())
+        }
+    }
+}
+
+// Runs the command on the database itself, for when the server isn't running
+pub async fn execute_offline(command: &Command) -> Result<String, AdminError> {
+    let db = storage::connect().await?;
+    let result = async {
+        if migrations::status(db.as_ref())
+            .await?
+            .iter()
+            .any(|(_, applied)| !applied)
+        {
+            return Err(AdminError::NotMigrated);
+        }
+        execute(command, db.as_ref(), None).await
+    }
+    .await;
+    db.close().await;
+    result
+}
+
+// The admin socket takes one command per connection: the args separated by tabs on a
+// line. The answer is "ok" or "error" on a line followed by the output
+
+// Sends the command to the running server, None when no server is listening
+#[cfg(unix)]
+pub async fn request(args: &[String]) -> Option<Result<String, AdminError>> {
+    let stream = UnixStream::connect(socket_path()).await.ok()?;
+    Some(match client_token() {
+        Ok(token) => send(stream, &token, args).await,
+        Err(err) => Err(err),
+    })
+}
+
+#[cfg(not(unix))]
+pub async fn request(_args: &[String]) -> Option<Result<String, AdminError>> {
+    None
+}
+
+#[cfg(unix)]
+async fn send(mut stream: UnixStream, token: &str, args: &[String]) -> Result<String, AdminError> {
+    stream
+        .write_all(format!("{}\n{}\n", token, args.join("\t")).as_bytes())
+        .await?;
+    let mut res = String::new();
+    stream.read_to_string(&mut res).await?;
+    match res.split_once('\n') {
+        Some(("ok", out)) => Ok(out.to_string()),
+        Some(("error", err)) => Err(AdminError::Refused(err.to_string())),
+        _ => Err(AdminError::Refused(
+            "malformed answer from the server".to_string(),
+        )),
+    }
+}
+
+// Bound in a directory only the owner can enter and restricted before it's moved in place,
+// nobody else can connect in between
+#[cfg(unix)]
+fn bind_private(path: &std::path::Path) -> std::io::Result<UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    let mut dir = path.as_os_str().to_owned();
+    dir.push(".tmp");
+    let dir = PathBuf::from(dir);
+    let temp = dir.join("socket");
+    // Left behind by a server that didn't stop cleanly
+    let _ = std::fs::remove_file(&temp);
+    let _ = std::fs::remove_dir(&dir);
+    std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let listener = UnixListener::bind(&temp).and_then(|listener| {
+        std::fs::set_permissions(&temp, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&temp, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&temp);
+    let _ = std::fs::remove_dir(&dir);
+    listener
+}
+
+#[cfg(unix)]
+pub async fn serve(shared: Shared) {
+    let token = match server_token() {
+        Ok(token) => token,
+        Err(err) => {
+            warn!(%err, "couldn't create the admin token, the admin socket is closed");
+            return;
+        }
+    };
+    let path = socket_path();
+    // Left behind by a server that didn't stop cleanly
+    let _ = std::fs::remove_file(&path);
+    // Only the user running the server can use it
+    let listener = match bind_private(&path) {
+        Ok(listener) => listener,
+        Err(err) => {
+            warn!(%err, path = %path.display(), "couldn't open the admin socket");
+            return;
+        }
+    };
+    info!(path = %path.display(), "admin socket listening");
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(handle(stream, token.clone(), shared.clone()));
+            }
+            Err(err) => warn!(%err, "couldn't accept an admin connection"),
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn handle(stream: UnixStream, token: String, shared: Shared) {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader.take(MAX_REQUEST_SIZE));
+    let mut given = String::new();
+    let mut line = String::new();
+    let read = match reader.read_line(&mut given).await {
+        Ok(_) => reader.read_line(&mut line).await,
+        Err(err) => Err(err),
+    };
+    if let Err(err) = read {
+        warn!(%err, "couldn't read an admin command");
+        return;
+    }
+    let args: Vec<String> = line
+        .trim_end_matches('\n')
+        .split('\t')
+        .map(str::to_string)
+        .collect();
+    // Compared in constant time, how long it takes tells nothing about the token
+    let authorized: bool = given
+        .trim_end_matches('\n')
+        .as_bytes()
+        .ct_eq(token.as_bytes())
+        .into();
+    let res = match Command::parse(&args) {
+        _ if !authorized => Err(AdminError::BadToken),
+        Some(command) => {
+            info!(command = %args[0], "admin command");
+            execute(&command, shared.db.as_ref(), Some(&shared)).await
+        }
+        None => Err(AdminError::Refused(USAGE.to_string())),
+    };
+    let res = match res {
+        Ok(out) => format!("ok\n{}", out),
+        Err(err) => {
+            warn!(%err, "admin command failed");
+            format!("error\n{}", err)
+        }
+    };
+    if let Err(err) = writer.write_all(res.as_bytes()).await {
+        warn!(%err, "couldn't answer an admin command");
+    }
+}
+
+// The socket file is left behind otherwise
+#[cfg(unix)]
+pub fn remove_socket() {
+    let _ = std::fs::remove_file(socket_path());
+    let _ = std::fs::remove_file(token_path());
+}
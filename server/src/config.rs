@@ -0,0 +1,206 @@
+use crate::{
+    logging::LogLevels,
+    rate_limit::{Limit, RateLimiter, RateLimits},
+};
+use serde::Deserialize;
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+// Where the config is read from, overridden by CONFIG_PATH
+const DEFAULT_PATH: &str = "chat-console.toml";
+// Smaller frames couldn't even hold a login
+const MIN_FRAME_SIZE: usize = 1024;
+
+// Settings that can change while the server runs, anything missing from the file keeps its
+// default
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // Address clients connect to, only read when the server starts
+    pub listen_addr: String,
+    // Biggest frame a client can send, images are the biggest msgs
+    pub max_frame_size: usize,
+    pub rate_limits: RateLimits,
+    // Replaced with asterisks in text msgs, whatever their case
+    pub banned_words: Vec<String>,
+    // Sent to users when they login
+    pub motd: Option<String>,
+    // Same syntax as RUST_LOG, which is used when unset
+    pub log_level: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen_addr: "127.0.0.1:8000".to_string(),
+            max_frame_size: 16 * 1024 * 1024,
+            rate_limits: RateLimits::default(),
+            banned_words: Vec::new(),
+            motd: None,
+            log_level: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    Parse(toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(err) => write!(f, "couldn't read the config: {}", err),
+            ConfigError::Parse(err) => write!(f, "invalid config: {}", err),
+            ConfigError::Invalid(err) => write!(f, "invalid config: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<std::io::Error> for ConfigError {
+    fn from(err: std::io::Error) -> Self {
+        ConfigError::Io(err)
+    }
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(err: toml::de::Error) -> Self {
+        ConfigError::Parse(err)
+    }
+}
+
+pub fn path() -> PathBuf {
+    std::env::var("CONFIG_PATH")
+        .unwrap_or_else(|_| DEFAULT_PATH.to_string())
+        .into()
+}
+
+impl Config {
+    // Without a file everything has its default
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(err) => return Err(err.into()),
+        };
+        let mut config: Config = toml::from_str(&text)?;
+        config.validate()?;
+        config.banned_words = config
+            .banned_words
+            .iter()
+            .map(|word| word.to_lowercase())
+            .collect();
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.max_frame_size < MIN_FRAME_SIZE {
+            return Err(ConfigError::Invalid(format!(
+                "max_frame_size must be at least {}",
+                MIN_FRAME_SIZE
+            )));
+        }
+        let limits = &self.rate_limits;
+        for (name, limit) in [
+            ("message", limits.message),
+            ("image", limits.image),
+            ("login", limits.login),
+            ("signup", limits.signup),
+        ] {
+            validate_limit(name, limit)?;
+        }
+        if limits.shared_factor < 1.0 {
+            return Err(ConfigError::Invalid(
+                "rate_limits.shared_factor can't be below 1".to_string(),
+            ));
+        }
+        if let Some(word) = self
+            .banned_words
+            .iter()
+            .find(|word| word.is_empty() || word.contains(|c: char| !c.is_alphanumeric()))
+        {
+            return Err(ConfigError::Invalid(format!(
+                "banned word {:?} isn't a single word",
+                word
+            )));
+        }
+        Ok(())
+    }
+
+    // The text with the banned words covered, None when there are none
+    pub fn censor(&self, text: &str) -> Option<String> {
+        if self.banned_words.is_empty() {
+            return None;
+        }
+        let mut censored = String::with_capacity(text.len());
+        let mut found = false;
+        let mut rest = text;
+        while !rest.is_empty() {
+            let end = rest
+                .find(|c: char| !c.is_alphanumeric())
+                .unwrap_or(rest.len());
+            let (word, after) = rest.split_at(end);
+            if !word.is_empty() && self.banned_words.contains(&word.to_lowercase()) {
+                found = true;
+                censored.extend(word.chars().map(|_| '*'));
+            } else {
+                censored.push_str(word);
+            }
+            let mut chars = after.chars();
+            if let Some(separator) = chars.next() {
+                censored.push(separator);
+            }
+            rest = chars.as_str();
+        }
+        found.then_some(censored)
+    }
+}
+
+fn validate_limit(name: &str, limit: Limit) -> Result<(), ConfigError> {
+    if limit.burst < 1.0 || limit.per_second <= 0.0 {
+        return Err(ConfigError::Invalid(format!(
+            "rate_limits.{} needs a burst of at least 1 and a positive per_second",
+            name
+        )));
+    }
+    Ok(())
+}
+
+// The config in use. Requests read it as a whole, so a reload never mixes two configs
+pub struct Settings {
+    path: PathBuf,
+    current: RwLock<Arc<Config>>,
+    log_levels: LogLevels,
+}
+
+impl Settings {
+    pub fn new(path: PathBuf, config: Config, log_levels: LogLevels) -> Self {
+        Self {
+            path,
+            current: RwLock::new(Arc::new(config)),
+            log_levels,
+        }
+    }
+
+    pub fn get(&self) -> Arc<Config> {
+        self.current.read().unwrap().clone()
+    }
+
+    // Reads the file again. An invalid one changes nothing, the old config stays
+    pub fn reload(&self, limiter: &RateLimiter) -> Result<(), ConfigError> {
+        let config = Config::load(&self.path)?;
+        self.log_levels
+            .set(config.log_level.as_deref())
+            .map_err(ConfigError::Invalid)?;
+        limiter.set_limits(config.rate_limits.clone());
+        *self.current.write().unwrap() = Arc::new(config);
+        Ok(())
+    }
+}
@@ -0,0 +1,86 @@
+use shared_utils::{ErrorCode, ErrorMsg};
+use std::fmt;
+
+// Why handling a connection or one of its requests failed
+#[derive(Debug)]
+pub enum ServerError {
+    // Reading from the socket failed
+    Io(std::io::Error),
+    // The header announced a frame bigger than MAX_FRAME_SIZE
+    FrameTooBig(usize),
+    // The frame isn't a MsgType
+    Malformed(String),
+    Database(sqlx::Error),
+    Hash(bcrypt::BcryptError),
+    // The outbound queue is full
+    SlowClient,
+    // The writer task gave up on the client
+    Closed,
+    Flooding,
+}
+
+impl ServerError {
+    // Whether the connection can't go on after it
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            ServerError::Io(_)
+            | ServerError::FrameTooBig(_)
+            | ServerError::SlowClient
+            | ServerError::Closed
+            | ServerError::Flooding => true,
+            ServerError::Malformed(_) | ServerError::Database(_) | ServerError::Hash(_) => false,
+        }
+    }
+
+    // What the client is told, internal details stay in the log
+    pub fn client_error(&self) -> ErrorMsg {
+        match self {
+            ServerError::FrameTooBig(_) => ErrorMsg::new(ErrorCode::MsgTooBig),
+            ServerError::Malformed(_) => ErrorMsg::new(ErrorCode::Malformed),
+            ServerError::Flooding => ErrorMsg::with_detail(
+                ErrorCode::RateLimited,
+                "Disconnected for flooding.".to_string(),
+            ),
+            ServerError::Io(_)
+            | ServerError::Database(_)
+            | ServerError::Hash(_)
+            | ServerError::SlowClient
+            | ServerError::Closed => ErrorMsg::new(ErrorCode::Internal),
+        }
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerError::Io(err) => write!(f, "io error: {}", err),
+            ServerError::FrameTooBig(len) => write!(f, "frame of {} bytes is too big", len),
+            ServerError::Malformed(err) => write!(f, "malformed frame: {}", err),
+            ServerError::Database(err) => write!(f, "database error: {}", err),
+            ServerError::Hash(err) => write!(f, "password hash error: {}", err),
+            ServerError::SlowClient => write!(f, "client can't keep up"),
+            ServerError::Closed => write!(f, "connection closed"),
+            ServerError::Flooding => write!(f, "client is flooding"),
+        }
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+impl From<std::io::Error> for ServerError {
+    fn from(err: std::io::Error) -> Self {
+        ServerError::Io(err)
+    }
+}
+
+impl From<sqlx::Error> for ServerError {
+    fn from(err: sqlx::Error) -> Self {
+        ServerError::Database(err)
+    }
+}
+
+impl From<bcrypt::BcryptError> for ServerError {
+    fn from(err: bcrypt::BcryptError) -> Self {
+        ServerError::Hash(err)
+    }
+}
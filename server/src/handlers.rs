@@ -1,134 +1,809 @@
+#[cfg(test)]
+mod tests;
+
+use crate::{
+    config::Settings,
+    error::ServerError,
+    logging::{msg_type_name, RequestSpan},
+    login_guard,
+    metrics::Metrics,
+    rate_limit::{Action, Penalty, RateLimiter, Strikes, THROTTLE_TIME},
+    registry::{ConnectionId, Delivery, Registry},
+    storage::{self, NewMessage, Storage, User},
+    tokens::Tokens,
+};
 use bcrypt::{hash, verify, DEFAULT_COST};
-use hmac::{Hmac, Mac};
-use jwt::{SignWithKey, VerifyWithKey};
-use sha2::Sha256;
 use shared_utils::{
-    decode_header, decode_msg_type, encode_msg_type, MsgType, ServerMsg, ServerRes, TokenMsg,
-    MSG_SIZE_BYTES,
+    decode_header, decode_msg_type, encode_msg_data, encode_msg_type, DeleteMsg, ErrorCode,
+    ErrorMsg, LoginMsg, ModerationAction, ModerationMsg, MsgDataType, MsgType, OfflineSummaryMsg,
+    Permission, ReadMarkerMsg, RequestId, ResponseMsg, ResumeMsg, Role, ServerMsg, ServerRes,
+    TokenMsg, UserMsg, DEFAULT_ROOM, MAX_MUTE, MSG_SIZE_BYTES, PING_INTERVAL,
+};
+use std::{
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
 };
-use sqlx::{Pool, Sqlite};
-use std::collections::BTreeMap;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-    sync::broadcast::{Receiver, Sender},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    sync::mpsc::{self, error::TrySendError},
+    time::{sleep_until, timeout, Duration, Instant},
 };
+use tracing::{error, field, info, info_span, warn, Instrument, Span};
 
-const SECRET: &str = "SECRETO";
+// Msgs from other connections waiting to be handled
+const INBOX_SIZE: usize = 256;
+// Msgs waiting to be written to a connection before it's considered too slow
+const OUTBOUND_QUEUE_SIZE: usize = 256;
+// Time a client can stay silent, it pings every PING_INTERVAL while connected
+const IDLE_TIMEOUT: Duration = Duration::from_secs(4 * PING_INTERVAL.as_secs());
+// Time a client has to read a msg
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
 
-#[derive(sqlx::FromRow)]
-struct User {
-    pub id: i64,
-    pub name: String,
-    pub password: String,
+// What every connection task gets a handle to
+#[derive(Clone)]
+pub struct Shared {
+    pub registry: Arc<Registry>,
+    pub db: Arc<dyn Storage>,
+    pub limiter: Arc<RateLimiter>,
+    pub metrics: Arc<Metrics>,
+    // Set from the admin socket, only admins can login while it's on
+    pub maintenance: Arc<AtomicBool>,
+    pub settings: Arc<Settings>,
+    pub tokens: Arc<Tokens>,
 }
 
-fn verify_jwt(token: String) -> Result<(), jwt::Error> {
-    let key: Hmac<Sha256> = Hmac::new_from_slice(SECRET.as_bytes())?;
-    let _claims: BTreeMap<String, i64> = token.verify_with_key(&key)?;
-    Ok(())
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs() as i64)
+        .unwrap_or(0)
 }
 
-pub fn new_conection(
-    mut socket: TcpStream,
-    addr: String,
-    tx: Sender<(String, bool, Vec<u8>)>,
-    mut rx: Receiver<(String, bool, Vec<u8>)>,
-    db: Pool<Sqlite>,
+// Usernames mentioned with "@name" in a text msg
+fn mentions(text: &str) -> Vec<String> {
+    let mut names: Vec<String> = text
+        .split_whitespace()
+        .filter_map(|word| word.strip_prefix('@'))
+        .map(|name| {
+            name.trim_end_matches(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .to_string()
+        })
+        .filter(|name| !name.is_empty())
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+// Why the user can't login, if banned
+async fn ban_error(db: &dyn Storage, user_id: i64) -> Result<Option<ErrorMsg>, sqlx::Error> {
+    let Some(ban) = db.active_ban(user_id, now()).await? else {
+        return Ok(None);
+    };
+    let detail = match ban.until {
+        Some(until) => format!(
+            "You are banned for {} more minutes: {}",
+            (until - now()) / 60 + 1,
+            ban.reason
+        ),
+        None => format!("You are banned: {}", ban.reason),
+    };
+    Ok(Some(ErrorMsg::with_detail(ErrorCode::Banned, detail)))
+}
+
+// The response to the request with the id, or a plain server msg when it has none
+fn response(request_id: Option<RequestId>, res: ServerRes) -> Vec<u8> {
+    match request_id {
+        Some(id) => encode_msg_type(&MsgType::Response(ResponseMsg { id, res })),
+        None => encode_msg_type(&MsgType::Server(res)),
+    }
+}
+
+pub fn notice(text: String) -> Vec<u8> {
+    encode_msg_type(&MsgType::Server(ServerRes::Notice(text)))
+}
+
+// Tells the client why it's being closed and not to come back on its own
+pub fn kicked(text: String) -> Vec<u8> {
+    encode_msg_type(&MsgType::Server(ServerRes::Kicked(text)))
+}
+
+// Central permission check of the requests made with a token. The role is read every time,
+// it may have changed since the token was signed
+async fn authorize(db: &dyn Storage, tokens: &Tokens, msg: &MsgType) -> Result<bool, sqlx::Error> {
+    let (token, permission) = match msg {
+        MsgType::MsgOut(msg) => match msg.data {
+            MsgDataType::Text(_) => (&msg.token, Permission::SendMessage),
+            MsgDataType::Image(_) => (&msg.token, Permission::UploadImage),
+        },
+        MsgType::Delete(msg) => (&msg.token, Permission::DeleteOthersMessages),
+        MsgType::Moderate(msg) => match msg.action {
+            ModerationAction::Kick => (&msg.token, Permission::Kick),
+            ModerationAction::Mute { .. } => (&msg.token, Permission::Mute),
+            ModerationAction::Ban { .. } => (&msg.token, Permission::Ban),
+        },
+        _ => return Ok(true),
+    };
+    // Unsigned requests are refused by their own handler
+    let Some(user_id) = tokens.user_id(db, token).await? else {
+        return Ok(true);
+    };
+    // Anyone can delete their own msgs
+    if let MsgType::Delete(msg) = msg {
+        if db.message_owner(msg.id).await? == Some(user_id) {
+            return Ok(true);
+        }
+    }
+    Ok(db.user_role(user_id).await?.can(permission))
+}
+
+// Responses for a user that just logged in or resumed a session: the token, the unread
+// counts, what was queued while offline and, when resuming, the msgs after last_seen_id
+async fn session_start(
+    db: &dyn Storage,
+    tokens: &Tokens,
+    user_id: i64,
+    username: String,
+    last_seen_id: Option<i64>,
+    request_id: Option<RequestId>,
+) -> Result<Vec<u8>, sqlx::Error> {
+    // A resumed session gets a new token too, so it doesn't expire while in use
+    let (token, expires_at) = tokens.issue(db, user_id).await?;
+    let role = db.user_role(user_id).await?;
+    let mut res = response(
+        request_id,
+        ServerRes::UserToken(TokenMsg {
+            token,
+            username,
+            role,
+            expires_at,
+        }),
+    );
+    let counts = db.unread_counts(user_id).await?;
+    res.extend(encode_msg_type(&MsgType::Server(ServerRes::UnreadCounts(
+        counts,
+    ))));
+
+    let replay = match last_seen_id {
+        Some(last_seen_id) => db.messages_since(user_id, last_seen_id).await?,
+        None => Vec::new(),
+    };
+    let mut queued = db.take_offline_queue(user_id).await?;
+    // Queued msgs that are replayed anyway aren't sent twice
+    let first_replayed = replay.first().map(|msg| msg.id).unwrap_or(i64::MAX);
+    queued.retain(|(_, msg)| msg.id < first_replayed);
+
+    if !queued.is_empty() {
+        let direct = queued
+            .iter()
+            .filter(|(kind, _)| kind == storage::QUEUE_DIRECT)
+            .count() as i64;
+        res.extend(encode_msg_type(&MsgType::Server(
+            ServerRes::OfflineSummary(OfflineSummaryMsg {
+                direct,
+                mentions: queued.len() as i64 - direct,
+            }),
+        )));
+    }
+    for msg in queued.into_iter().map(|(_, msg)| msg).chain(replay) {
+        res.extend(encode_msg_type(&MsgType::MsgIn(msg)));
+    }
+    Ok(res)
+}
+
+// Writes the queued msgs, so a client that reads slowly never stalls the handling of its
+// requests or the other connections
+async fn write_outbound(
+    mut writer: OwnedWriteHalf,
+    mut outbound: mpsc::Receiver<Vec<u8>>,
+    metrics: Arc<Metrics>,
 ) {
-    tokio::spawn(async move {
-        let (mut reader, mut writer) = socket.split();
-        let mut msg_len_buf = vec![0; MSG_SIZE_BYTES];
-        println!("Peer {:?} conected", addr);
-
-        loop {
-            tokio::select! {
-                bytes_readed = reader.read(&mut msg_len_buf) => {
-                    let n = bytes_readed.unwrap();
-                    if n == 0 {
-                        println!("Peer {:?} disconected", &addr);
-                        break;
+    while let Some(msg) = outbound.recv().await {
+        match timeout(WRITE_TIMEOUT, writer.write_all(&msg)).await {
+            Ok(Ok(())) => metrics.bytes_out.inc_by(msg.len() as u64),
+            Ok(Err(_)) => break,
+            Err(_) => {
+                metrics.slow_disconnects.inc();
+                break;
+            }
+        }
+    }
+}
+
+// The rest of a frame whose header started arriving, the header may come in pieces.
+// None when the client closed the connection
+async fn read_frame(
+    reader: &mut OwnedReadHalf,
+    header: &mut [u8],
+    bytes_readed: std::io::Result<usize>,
+    max_size: usize,
+) -> Result<Option<Vec<u8>>, ServerError> {
+    let n = bytes_readed?;
+    if n == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut header[n..]).await?;
+    let len = decode_header(header) as usize;
+    if len > max_size {
+        return Err(ServerError::FrameTooBig(len));
+    }
+    let mut buf = vec![0; len];
+    reader.read_exact(&mut buf).await?;
+    Ok(Some(buf))
+}
+
+// State of a connection task
+struct Peer {
+    id: ConnectionId,
+    addr: String,
+    ip: IpAddr,
+    // Id and name of the user logged in through this connection
+    logged: Option<(i64, String)>,
+    // Id of the request being handled, its replies are responses to it
+    request_id: Option<RequestId>,
+    responded: bool,
+    strikes: Strikes,
+    outbound: mpsc::Sender<Vec<u8>>,
+    registry: Arc<Registry>,
+    db: Arc<dyn Storage>,
+    limiter: Arc<RateLimiter>,
+    metrics: Arc<Metrics>,
+    maintenance: Arc<AtomicBool>,
+    settings: Arc<Settings>,
+    tokens: Arc<Tokens>,
+    // Span of the connection, the user id is recorded on it once logged in
+    span: Span,
+}
+
+impl Peer {
+    // Queues a msg for the writer task of the connection
+    fn send(&self, msg: Vec<u8>) -> Result<(), ServerError> {
+        match self.outbound.try_send(msg) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                self.metrics.dropped_msgs.inc();
+                Err(ServerError::SlowClient)
+            }
+            Err(TrySendError::Closed(_)) => Err(ServerError::Closed),
+        }
+    }
+
+    fn reply(&mut self, res: ServerRes) -> Result<(), ServerError> {
+        self.responded |= self.request_id.is_some();
+        self.send(response(self.request_id, res))
+    }
+
+    fn error(&mut self, error: ErrorMsg) -> Result<(), ServerError> {
+        self.reply(ServerRes::Error(error))
+    }
+
+    fn set_logged(&mut self, user: &User, event: &str) {
+        self.registry.login(self.id, user.id, &user.name);
+        self.span.record("user_id", user.id);
+        info!(username = %user.name, "{}", event);
+        self.logged = Some((user.id, user.name.clone()));
+    }
+
+    async fn request(&mut self, buf: &[u8]) -> Result<(), ServerError> {
+        self.metrics
+            .bytes_in
+            .inc_by((MSG_SIZE_BYTES + buf.len()) as u64);
+        let msg = decode_msg_type(buf).map_err(|err| ServerError::Malformed(err.to_string()))?;
+        let (request_id, msg) = match msg {
+            MsgType::Request(request) => (Some(request.id), request.msg.into()),
+            msg => (None, msg),
+        };
+        self.request_id = request_id;
+        self.responded = false;
+        let request = RequestSpan::new(&msg, request_id);
+        self.metrics
+            .requests
+            .with_label_values(&[msg_type_name(&msg)])
+            .inc();
+
+        // Pings are answered even when throttled, the client would think the connection is dead
+        if let MsgType::Ping(nonce) = msg {
+            return self.send(encode_msg_type(&MsgType::Pong(nonce)));
+        }
+        // Everything from a throttled connection is dropped
+        if self.strikes.is_throttled() {
+            return Ok(());
+        }
+        self.handle(msg).instrument(request.span.clone()).await?;
+        // So the client knows it's done with the request
+        if self.request_id.is_some() && !self.responded {
+            self.reply(ServerRes::Ack)?;
+        }
+        Ok(())
+    }
+
+    async fn handle(&mut self, msg: MsgType) -> Result<(), ServerError> {
+        let user_id = self.logged.as_ref().map(|(id, _)| *id);
+        if let Some(action) = Action::of(&msg) {
+            if !self.limiter.check(action, &self.addr, self.ip, user_id) {
+                let error = match self.strikes.strike() {
+                    Penalty::Warn => ErrorMsg::new(ErrorCode::RateLimited),
+                    Penalty::Throttle => ErrorMsg::with_detail(
+                        ErrorCode::RateLimited,
+                        format!(
+                            "You are sending too fast, your requests are ignored for {} seconds.",
+                            THROTTLE_TIME.as_secs()
+                        ),
+                    ),
+                    Penalty::Disconnect => return Err(ServerError::Flooding),
+                };
+                return self.error(error);
+            }
+        }
+        if !authorize(self.db.as_ref(), &self.tokens, &msg).await? {
+            return self.error(ErrorMsg::new(ErrorCode::Forbidden));
+        }
+        match msg {
+            MsgType::MsgOut(msg) => self.send_msg(msg).await,
+            MsgType::Login(msg) => self.login(msg).await,
+            MsgType::Signup(msg) => self.signup(msg).await,
+            MsgType::Resume(msg) => self.resume(msg).await,
+            MsgType::Logout => {
+                self.registry.logout(self.id);
+                self.logged = None;
+                Ok(())
+            }
+            MsgType::Delete(msg) => self.delete(msg).await,
+            MsgType::Moderate(msg) => self.moderate(msg).await,
+            MsgType::ReadMarker(msg) => self.read_marker(msg).await,
+            _ => Ok(()),
+        }
+    }
+
+    async fn send_msg(&mut self, mut msg: UserMsg) -> Result<(), ServerError> {
+        let Some(id) = self.tokens.user_id(self.db.as_ref(), &msg.token).await? else {
+            return self.error(ErrorMsg::new(ErrorCode::AuthExpired));
+        };
+        // The sender is whoever logged in through this connection, never a name the client
+        // picks
+        let username = match &self.logged {
+            Some((logged_id, name)) if *logged_id == id => name.clone(),
+            _ => return self.error(ErrorMsg::new(ErrorCode::AuthExpired)),
+        };
+        // Msgs of muted users are dropped
+        if let Some(until) = self.db.muted_until(id, now()).await? {
+            return self.error(ErrorMsg::with_detail(
+                ErrorCode::Muted,
+                format!(
+                    "You are muted for {} more minutes.",
+                    (until - now()) / 60 + 1
+                ),
+            ));
+        }
+        if let MsgDataType::Text(text) = &mut msg.data {
+            if let Some(censored) = self.settings.get().censor(text) {
+                *text = censored;
+            }
+        }
+        if let Some(to) = &msg.to {
+            if self.db.user_by_name(to).await?.is_none() {
+                return self.error(ErrorMsg::new(ErrorCode::UserNotFound));
+            }
+        }
+        let msg_id = self
+            .db
+            .insert_message(NewMessage {
+                room: &msg.room,
+                user_id: id,
+                username: &username,
+                recipient: msg.to.as_deref(),
+                data: encode_msg_data(&msg.data),
+            })
+            .await?;
+
+        if let MsgDataType::Image(image) = &msg.data {
+            self.metrics.image_bytes.observe(image.len() as f64);
+        }
+        // Direct msgs and mentions of offline users wait for their next login
+        match (&msg.to, &msg.data) {
+            (Some(to), _) if !self.registry.is_online(to) => {
+                self.db
+                    .queue_offline(to, msg_id, storage::QUEUE_DIRECT)
+                    .await?;
+            }
+            (None, MsgDataType::Text(text)) => {
+                for name in mentions(text) {
+                    if name != username && !self.registry.is_online(&name) {
+                        self.db
+                            .queue_offline(&name, msg_id, storage::QUEUE_MENTION)
+                            .await?;
                     }
-                    let len = decode_header(&msg_len_buf[..]);
-
-                    let mut buf = vec![0; len as usize];
-                    let _ = reader.read(&mut buf).await.unwrap();
-                    let msg = decode_msg_type(&buf).unwrap();
-                    match msg {
-                        MsgType::MsgOut(msg) => {
-                            let peer = reader.peer_addr().unwrap().to_string();
-                            match verify_jwt(msg.token.clone()) {
-                                Ok(_) => {},
-                                Err(err) => {println!("{}", err);}
-                            }
-                            if verify_jwt(msg.token.clone()).is_ok() {
-                                let msg = MsgType::MsgIn(ServerMsg {
-                                    username: msg.username,
-                                    data: msg.data
-                                });
-                                tx.send((peer, false, encode_msg_type(&msg))).unwrap();
-                                continue;
-                            }
-                            tx.send((peer, true, encode_msg_type(&MsgType::Server(ServerRes::Error("Msg is not signed.".to_string()))))).unwrap();
-                        },
-                        MsgType::Login(msg) => {
-                            let peer = reader.peer_addr().unwrap().to_string();
-                            if reader.peer_addr().unwrap().to_string() != addr {
-                                continue;
-                            }
-                            if let Ok(user) = sqlx::query_as::<_, User>("SELECT * FROM users WHERE name = ?")
-                            .bind(msg.username)
-                            .bind(&msg.password)
-                            .fetch_one(&db).await {
-                                if verify(msg.password, &user.password).unwrap() {
-                                    let key: Hmac<Sha256> = Hmac::new_from_slice(SECRET.as_bytes()).unwrap();
-                                    let mut claims = BTreeMap::new();
-                                    claims.insert("id", user.id);
-                                    let token_str = claims.sign_with_key(&key).unwrap();
-                                    tx.send((peer, true, encode_msg_type(&MsgType::Server(ServerRes::UserToken(TokenMsg {
-                                        token: token_str,
-                                        username: user.name
-                                    }))))).unwrap();
-                                    continue;
-                                }
-                            }
-                            tx.send((peer, true, encode_msg_type(&MsgType::Server(ServerRes::Error("The username or password are incorrect!.".to_string()))))).unwrap();
-                        },
-                        MsgType::Signup(msg) => {
-                            let peer = reader.peer_addr().unwrap().to_string();
-                            if reader.peer_addr().unwrap().to_string() != addr {
-                                continue;
-                            }
-                            let hashed = hash(msg.password, DEFAULT_COST).unwrap();
-                            match sqlx::query("INSERT INTO users (name, password) VALUES (?, ?);")
-                            .bind(msg.username)
-                            .bind(hashed)
-                            .execute(&db).await {
-                                Ok(_) => {
-                                    tx.send((peer, true, encode_msg_type(&MsgType::Server(ServerRes::UserCreated)))).unwrap();
-                                },
-                                Err(_) => {
-                                    tx.send((peer, true, encode_msg_type(&MsgType::Server(ServerRes::Error("User already exist!.".to_string()))))).unwrap();
-                                },
+                }
+            }
+            _ => {}
+        }
+
+        let res = encode_msg_type(&MsgType::MsgIn(ServerMsg {
+            id: msg_id,
+            username: username.clone(),
+            room: msg.room.clone(),
+            to: msg.to.clone(),
+            data: msg.data,
+        }));
+        match msg.to {
+            Some(to) => self.registry.to_users(&[username, to], res, &self.metrics),
+            None => {
+                // Posting in a room joins it
+                self.registry.join(self.id, &msg.room);
+                self.registry.to_room(&msg.room, res, &self.metrics);
+            }
+        }
+        Ok(())
+    }
+
+    async fn login(&mut self, msg: LoginMsg) -> Result<(), ServerError> {
+        if let Some(error) =
+            login_guard::lockout_error(self.db.as_ref(), Some(&msg.username), self.ip).await?
+        {
+            return self.error(error);
+        }
+        tokio::time::sleep(
+            login_guard::delay(self.db.as_ref(), Some(&msg.username), self.ip).await?,
+        )
+        .await;
+        let user = self.db.user_by_name(&msg.username).await?;
+        let user = match user {
+            Some(user) if verify(&msg.password, &user.password)? => user,
+            _ => {
+                login_guard::failure(
+                    self.db.as_ref(),
+                    &self.metrics,
+                    login_guard::LOGIN_FAILED,
+                    Some(&msg.username),
+                    self.ip,
+                )
+                .await?;
+                return self.error(ErrorMsg::new(ErrorCode::InvalidCredentials));
+            }
+        };
+        login_guard::success(self.db.as_ref(), &user.name).await?;
+        if let Some(error) = ban_error(self.db.as_ref(), user.id).await? {
+            return self.error(error);
+        }
+        if let Some(error) = self.kick_error(user.id) {
+            return self.error(error);
+        }
+        if let Some(error) = self.maintenance_error(user.id).await? {
+            return self.error(error);
+        }
+        self.set_logged(&user, "logged in");
+
+        self.start_session(user, None).await?;
+        if let Some(motd) = &self.settings.get().motd {
+            self.send(notice(motd.clone()))?;
+        }
+        Ok(())
+    }
+
+    // Why the user can't login, if kicked a moment ago
+    fn kick_error(&self, user_id: i64) -> Option<ErrorMsg> {
+        let left = self.registry.cooldown_left(user_id)?;
+        Some(ErrorMsg::with_detail(
+            ErrorCode::Kicked,
+            format!(
+                "You were kicked, you can login again in {} seconds.",
+                left.as_secs() + 1
+            ),
+        ))
+    }
+
+    async fn maintenance_error(&self, user_id: i64) -> Result<Option<ErrorMsg>, sqlx::Error> {
+        if self.maintenance.load(Ordering::Relaxed)
+            && self.db.user_role(user_id).await? < Role::Admin
+        {
+            return Ok(Some(ErrorMsg::new(ErrorCode::Maintenance)));
+        }
+        Ok(None)
+    }
+
+    async fn start_session(
+        &mut self,
+        user: User,
+        last_seen_id: Option<i64>,
+    ) -> Result<(), ServerError> {
+        let res = session_start(
+            self.db.as_ref(),
+            &self.tokens,
+            user.id,
+            user.name,
+            last_seen_id,
+            self.request_id,
+        )
+        .await?;
+        self.responded = true;
+        self.send(res)
+    }
+
+    async fn signup(&mut self, msg: LoginMsg) -> Result<(), ServerError> {
+        if self.maintenance.load(Ordering::Relaxed) {
+            return self.error(ErrorMsg::new(ErrorCode::Maintenance));
+        }
+        // Taken usernames are failures too, trying many of them finds the accounts
+        if let Some(error) = login_guard::lockout_error(self.db.as_ref(), None, self.ip).await? {
+            return self.error(error);
+        }
+        tokio::time::sleep(login_guard::delay(self.db.as_ref(), None, self.ip).await?).await;
+        let hashed = hash(msg.password, DEFAULT_COST)?;
+        // The first user of the server owns it
+        if self.db.create_user(&msg.username, &hashed).await? {
+            self.reply(ServerRes::UserCreated)
+        } else {
+            login_guard::failure(
+                self.db.as_ref(),
+                &self.metrics,
+                login_guard::SIGNUP_FAILED,
+                None,
+                self.ip,
+            )
+            .await?;
+            self.error(ErrorMsg::new(ErrorCode::UsernameTaken))
+        }
+    }
+
+    async fn resume(&mut self, msg: ResumeMsg) -> Result<(), ServerError> {
+        let user = match self.tokens.user_id(self.db.as_ref(), &msg.token).await? {
+            Some(id) => self.db.user_by_id(id).await?,
+            None => None,
+        };
+        let Some(user) = user else {
+            return self.error(ErrorMsg::new(ErrorCode::AuthExpired));
+        };
+        if let Some(error) = ban_error(self.db.as_ref(), user.id).await? {
+            return self.error(error);
+        }
+        if let Some(error) = self.kick_error(user.id) {
+            return self.error(error);
+        }
+        if let Some(error) = self.maintenance_error(user.id).await? {
+            return self.error(error);
+        }
+        self.set_logged(&user, "session resumed");
+
+        self.start_session(user, msg.last_seen_id).await
+    }
+
+    async fn delete(&mut self, msg: DeleteMsg) -> Result<(), ServerError> {
+        if self
+            .tokens
+            .user_id(self.db.as_ref(), &msg.token)
+            .await?
+            .is_none()
+        {
+            return self.error(ErrorMsg::new(ErrorCode::AuthExpired));
+        }
+        if self.db.delete_message(msg.id).await? {
+            self.registry.to_logged(
+                encode_msg_type(&MsgType::Server(ServerRes::MsgDeleted(msg.id))),
+                &self.metrics,
+            );
+        }
+        Ok(())
+    }
+
+    async fn moderate(&mut self, msg: ModerationMsg) -> Result<(), ServerError> {
+        let Some(id) = self.tokens.user_id(self.db.as_ref(), &msg.token).await? else {
+            return self.error(ErrorMsg::new(ErrorCode::AuthExpired));
+        };
+        let target = self.db.user_by_name(&msg.username).await?;
+        let moderator = self.db.user_by_id(id).await?;
+        let (Some(target), Some(moderator)) = (target, moderator) else {
+            return self.error(ErrorMsg::new(ErrorCode::UserNotFound));
+        };
+        // Only users with a lower role can be moderated
+        if self.db.user_role(target.id).await? >= self.db.user_role(moderator.id).await? {
+            return self.error(ErrorMsg::with_detail(
+                ErrorCode::Forbidden,
+                "You can't moderate that user.".to_string(),
+            ));
+        }
+
+        let room_notice = match msg.action {
+            ModerationAction::Kick => {
+                self.registry.cool_down(target.id);
+                self.registry.kick(
+                    &target.name,
+                    kicked(format!("You were kicked by {}.", moderator.name)),
+                    &self.metrics,
+                );
+                info!(target = %target.name, "kicked");
+                format!("{} was kicked by {}.", target.name, moderator.name)
+            }
+            ModerationAction::Mute { duration } => {
+                let until = match now().checked_add(duration) {
+                    Some(until) if (1..=MAX_MUTE).contains(&duration) => until,
+                    _ => {
+                        return self.error(ErrorMsg::with_detail(
+                            ErrorCode::Malformed,
+                            "Mutes last from a second up to a year.".to_string(),
+                        ))
+                    }
+                };
+                self.db.mute_user(target.id, until, moderator.id).await?;
+                info!(target = %target.name, duration, "muted");
+                format!(
+                    "{} was muted for {} minutes by {}.",
+                    target.name,
+                    duration / 60,
+                    moderator.name
+                )
+            }
+            ModerationAction::Ban { reason, until } => {
+                self.db
+                    .ban_user(target.id, &reason, until, Some(moderator.id))
+                    .await?;
+                self.registry.kick(
+                    &target.name,
+                    notice(format!("You were banned by {}: {}", moderator.name, reason)),
+                    &self.metrics,
+                );
+                info!(target = %target.name, %reason, ?until, "banned");
+                format!(
+                    "{} was banned by {}: {}",
+                    target.name, moderator.name, reason
+                )
+            }
+        };
+        self.registry
+            .to_room(DEFAULT_ROOM, notice(room_notice), &self.metrics);
+        Ok(())
+    }
+
+    async fn read_marker(&mut self, msg: ReadMarkerMsg) -> Result<(), ServerError> {
+        if let Some(id) = self.tokens.user_id(self.db.as_ref(), &msg.token).await? {
+            self.db
+                .set_read_marker(id, &msg.room, msg.last_read_id)
+                .await?;
+            let counts = self.db.unread_counts(id).await?;
+            self.reply(ServerRes::UnreadCounts(counts))?;
+        }
+        Ok(())
+    }
+
+    // A msg from another connection. The sender gets its own msg back too, with the id the
+    // server gave it. Clients count unread msgs themselves, the counts are only sent on login
+    // and when a read marker moves
+    fn deliver(&mut self, msg: Vec<u8>) -> Result<(), ServerError> {
+        self.send(msg)
+    }
+}
+
+pub fn new_conection(socket: TcpStream, addr: String, shared: Shared) {
+    let span = info_span!("connection", peer = %addr, user_id = field::Empty);
+    tokio::spawn(
+        async move {
+            let ip = match socket.peer_addr() {
+                Ok(peer_addr) => peer_addr.ip(),
+                Err(err) => {
+                    warn!(%err, "peer gone before its connection was set up");
+                    return;
+                }
+            };
+            let (inbox_tx, mut inbox) = mpsc::channel(INBOX_SIZE);
+            let (mut reader, writer) = socket.into_split();
+            let (outbound, outbound_rx) = mpsc::channel(OUTBOUND_QUEUE_SIZE);
+            let mut writer_task =
+                tokio::spawn(write_outbound(writer, outbound_rx, shared.metrics.clone()));
+            let mut peer = Peer {
+                id: shared.registry.register(inbox_tx, &addr),
+                addr,
+                ip,
+                logged: None,
+                request_id: None,
+                responded: false,
+                strikes: Strikes::default(),
+                outbound,
+                registry: shared.registry,
+                db: shared.db,
+                limiter: shared.limiter,
+                metrics: shared.metrics,
+                maintenance: shared.maintenance,
+                settings: shared.settings,
+                tokens: shared.tokens,
+                span: Span::current(),
+            };
+            let mut msg_len_buf = vec![0; MSG_SIZE_BYTES];
+            info!("peer connected");
+
+            let mut last_read = Instant::now();
+
+            loop {
+                let handled = tokio::select! {
+                    bytes_readed = reader.read(&mut msg_len_buf) => {
+                        last_read = Instant::now();
+                        // A frame that stops halfway counts as silence too
+                        let max_size = peer.settings.get().max_frame_size;
+                        let frame = timeout(IDLE_TIMEOUT, read_frame(&mut reader, &mut msg_len_buf, bytes_readed, max_size))
+                            .await
+                            .unwrap_or_else(|_| Err(ServerError::Io(std::io::ErrorKind::TimedOut.into())));
+                        match frame {
+                            Ok(Some(buf)) => peer.request(&buf).await,
+                            Ok(None) => {
+                                info!("peer disconnected");
+                                break;
                             }
+                            Err(err) => Err(err),
                         }
-                        _ => {}
                     }
-                },
-                msg = rx.recv() => {
-                    let (sender_addr, to_me, msg) = msg.unwrap();
-                    if to_me {
-                        if sender_addr == addr {
-                            writer.write_all(&msg[..]).await.unwrap();
+                    // Half open connections never send anything again
+                    _ = sleep_until(last_read + IDLE_TIMEOUT) => {
+                        info!("peer timed out");
+                        break;
+                    }
+                    delivery = inbox.recv() => match delivery {
+                        Some(Delivery::Msg(msg)) => peer.deliver(msg),
+                        Some(Delivery::Close(msg)) => {
+                            let _ = peer.send(msg);
+                            info!("peer closed by the server");
+                            break;
+                        }
+                        // The registry dropped the connection for not keeping up. Once disconnected the
+                        // client resumes the session, which replays the missed msgs from the database
+                        None => {
+                            peer.metrics.slow_disconnects.inc();
+                            let _ = peer.send(notice("You fell behind, reconnecting.".to_string()));
+                            warn!("peer disconnected, it fell behind");
+                            break;
                         }
-                        continue;
+                    },
+                    // The writer task gave up on the client
+                    _ = peer.outbound.closed() => {
+                        warn!("peer disconnected, it stopped reading");
+                        break;
                     }
-                    if sender_addr != addr {
-                        writer.write_all(&msg[..]).await.unwrap();
+                };
+
+                if let Err(err) = handled {
+                    match &err {
+                        ServerError::Database(_) | ServerError::Hash(_) => {
+                            error!(%err, "request failed")
+                        }
+                        _ => warn!(%err, "request failed"),
+                    }
+                    if err.is_fatal() {
+                        if let ServerError::SlowClient = err {
+                            peer.metrics.slow_disconnects.inc();
+                        }
+                        let _ = peer.error(err.client_error());
+                        break;
+                    }
+                    if peer.error(err.client_error()).is_err() {
+                        break;
                     }
-                    // println!("{:?}", msg);
                 }
+                // Msgs from other connections aren't responses
+                peer.request_id = None;
             }
+
+            // Whatever is still queued is written, unless the client doesn't read it in time
+            let Peer {
+                id,
+                addr,
+                outbound,
+                registry,
+                limiter,
+                ..
+            } = peer;
+            drop(outbound);
+            if timeout(WRITE_TIMEOUT, &mut writer_task).await.is_err() {
+                writer_task.abort();
+            }
+
+            registry.unregister(id);
+            limiter.forget_connection(&addr);
         }
-    });
+        .instrument(span),
+    );
 }
@@ -0,0 +1,337 @@
+use super::{new_conection, Shared};
+use crate::{
+    config::{Config, Settings},
+    logging,
+    metrics::Metrics,
+    rate_limit::RateLimiter,
+    registry::Registry,
+    storage::memory::MemoryStorage,
+    tokens::Tokens,
+};
+use shared_utils::{
+    decode_header, decode_msg_type, encode_msg_type, DeleteMsg, ErrorCode, LoginMsg,
+    ModerationAction, ModerationMsg, MsgDataType, MsgType, RequestId, RequestKind, RequestMsg,
+    ResumeMsg, Role, ServerMsg, ServerRes, TokenMsg, UserMsg, DEFAULT_ROOM, MSG_SIZE_BYTES,
+};
+use std::{
+    path::PathBuf,
+    sync::{atomic::AtomicBool, Arc, LazyLock},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::{timeout, Duration},
+};
+
+// Time the server has to answer, logins hash a password
+const REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+// The counters are registered globally, every server of the tests shares them
+static METRICS: LazyLock<Arc<Metrics>> = LazyLock::new(|| Arc::new(Metrics::new()));
+
+// A server on a loopback port with an empty memory storage
+async fn serve() -> (String, Shared) {
+    let db = Arc::new(MemoryStorage::default());
+    crate::migrations::run(db.as_ref()).await.unwrap();
+    let config = Config::default();
+    let shared = Shared {
+        registry: Arc::new(Registry::default()),
+        db,
+        limiter: Arc::new(RateLimiter::new(config.rate_limits.clone())),
+        metrics: METRICS.clone(),
+        maintenance: Arc::new(AtomicBool::new(false)),
+        settings: Arc::new(Settings::new(
+            PathBuf::new(),
+            config,
+            logging::detached("info"),
+        )),
+        tokens: Arc::new(Tokens::new("a secret only the tests sign with")),
+    };
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let accepting = shared.clone();
+    tokio::spawn(async move {
+        while let Ok((socket, peer)) = listener.accept().await {
+            new_conection(socket, peer.to_string(), accepting.clone());
+        }
+    });
+    (addr, shared)
+}
+
+struct Client {
+    socket: TcpStream,
+    next_request_id: RequestId,
+}
+
+impl Client {
+    async fn connect(addr: &str) -> Client {
+        Client {
+            socket: TcpStream::connect(addr).await.unwrap(),
+            next_request_id: 0,
+        }
+    }
+
+    async fn send(&mut self, msg: MsgType) {
+        self.socket.write_all(&encode_msg_type(&msg)).await.unwrap();
+    }
+
+    async fn recv(&mut self) -> MsgType {
+        timeout(REPLY_TIMEOUT, async {
+            let mut header = [0; MSG_SIZE_BYTES];
+            self.socket.read_exact(&mut header).await.unwrap();
+            let mut buf = vec![0; decode_header(&header) as usize];
+            self.socket.read_exact(&mut buf).await.unwrap();
+            decode_msg_type(&buf).unwrap()
+        })
+        .await
+        .expect("the server didn't answer")
+    }
+
+    // The response to the request, whatever comes before it is a failure
+    async fn request(&mut self, msg: RequestKind) -> ServerRes {
+        self.next_request_id += 1;
+        let id = self.next_request_id;
+        self.send(MsgType::Request(RequestMsg { id, msg })).await;
+        match self.recv().await {
+            MsgType::Response(response) if response.id == id => response.res,
+            msg => panic!("expected the response to request {}, got {:?}", id, msg),
+        }
+    }
+
+    async fn server_res(&mut self) -> ServerRes {
+        match self.recv().await {
+            MsgType::Server(res) => res,
+            msg => panic!("expected a server msg, got {:?}", msg),
+        }
+    }
+
+    async fn msg_in(&mut self) -> ServerMsg {
+        match self.recv().await {
+            MsgType::MsgIn(msg) => msg,
+            msg => panic!("expected a msg, got {:?}", msg),
+        }
+    }
+
+    async fn signup(&mut self, username: &str) {
+        let res = self.request(RequestKind::Signup(login_msg(username))).await;
+        assert!(matches!(res, ServerRes::UserCreated), "{:?}", res);
+    }
+
+    // Logs in and reads the unread counts sent after the token
+    async fn login(&mut self, username: &str) -> TokenMsg {
+        let res = self.request(RequestKind::Login(login_msg(username))).await;
+        let ServerRes::UserToken(token) = res else {
+            panic!("expected a token, got {:?}", res);
+        };
+        assert!(matches!(
+            self.server_res().await,
+            ServerRes::UnreadCounts(_)
+        ));
+        token
+    }
+
+    // A text msg as echoed back, it comes through the inbox after the response
+    async fn post(&mut self, token: &str, to: Option<&str>, text: &str) -> ServerMsg {
+        let res = self
+            .request(RequestKind::MsgOut(text_msg(token, to, text)))
+            .await;
+        assert!(matches!(res, ServerRes::Ack), "{:?}", res);
+        self.msg_in().await
+    }
+}
+
+fn login_msg(username: &str) -> LoginMsg {
+    LoginMsg {
+        username: username.to_string(),
+        password: format!("{}-password", username),
+    }
+}
+
+fn text_msg(token: &str, to: Option<&str>, text: &str) -> UserMsg {
+    UserMsg {
+        room: DEFAULT_ROOM.to_string(),
+        to: to.map(str::to_string),
+        data: MsgDataType::Text(text.to_string()),
+        token: token.to_string(),
+    }
+}
+
+fn text(msg: &ServerMsg) -> &str {
+    match &msg.data {
+        MsgDataType::Text(text) => text,
+        MsgDataType::Image(_) => panic!("expected a text msg"),
+    }
+}
+
+fn error_code(res: &ServerRes) -> ErrorCode {
+    match res {
+        ServerRes::Error(error) => error.code,
+        res => panic!("expected an error, got {:?}", res),
+    }
+}
+
+// A signed up user logged in on a connection of their own
+async fn logged_in(addr: &str, username: &str) -> (Client, TokenMsg) {
+    let mut client = Client::connect(addr).await;
+    client.signup(username).await;
+    let token = client.login(username).await;
+    (client, token)
+}
+
+#[tokio::test]
+async fn signup_and_login() {
+    let (addr, _) = serve().await;
+    let mut ann = Client::connect(&addr).await;
+    ann.signup("ann").await;
+    let res = ann.request(RequestKind::Signup(login_msg("ann"))).await;
+    assert_eq!(error_code(&res), ErrorCode::UsernameTaken);
+
+    // The first user owns the server
+    let token = ann.login("ann").await;
+    assert_eq!((token.username.as_str(), token.role), ("ann", Role::Owner));
+    let (_, token) = logged_in(&addr, "ben").await;
+    assert_eq!(token.role, Role::Member);
+
+    let mut wrong = Client::connect(&addr).await;
+    let res = wrong
+        .request(RequestKind::Login(LoginMsg {
+            username: "ann".to_string(),
+            password: "wrong".to_string(),
+        }))
+        .await;
+    assert_eq!(error_code(&res), ErrorCode::InvalidCredentials);
+}
+
+#[tokio::test]
+async fn send_and_echo() {
+    let (addr, _) = serve().await;
+    let (mut ann, ann_token) = logged_in(&addr, "ann").await;
+    let (mut ben, _) = logged_in(&addr, "ben").await;
+
+    // The sender gets its msg back with its id, the rest of the room gets the same msg
+    let sent = ann.post(&ann_token.token, None, "hello").await;
+    assert_eq!((sent.username.as_str(), text(&sent)), ("ann", "hello"));
+    let received = ben.msg_in().await;
+    assert_eq!((received.id, text(&received)), (sent.id, "hello"));
+
+    // Msgs are sent as whoever logged in on the connection, not with a token of someone else
+    let mut stranger = Client::connect(&addr).await;
+    let res = stranger
+        .request(RequestKind::MsgOut(text_msg(&ann_token.token, None, "hi")))
+        .await;
+    assert_eq!(error_code(&res), ErrorCode::AuthExpired);
+    let res = ann
+        .request(RequestKind::MsgOut(text_msg("forged", None, "hi")))
+        .await;
+    assert_eq!(error_code(&res), ErrorCode::AuthExpired);
+}
+
+#[tokio::test]
+async fn resume_replay() {
+    let (addr, _) = serve().await;
+    let (mut ann, token) = logged_in(&addr, "ann").await;
+    let first = ann.post(&token.token, None, "first").await;
+    let second = ann.post(&token.token, None, "second").await;
+    drop(ann);
+
+    // Only the msgs after last_seen_id are sent again, after the new token and the counts
+    let mut ann = Client::connect(&addr).await;
+    let res = ann
+        .request(RequestKind::Resume(ResumeMsg {
+            token: token.token.clone(),
+            last_seen_id: Some(first.id),
+        }))
+        .await;
+    assert!(matches!(res, ServerRes::UserToken(_)), "{:?}", res);
+    assert!(matches!(ann.server_res().await, ServerRes::UnreadCounts(_)));
+    let replayed = ann.msg_in().await;
+    assert_eq!((replayed.id, text(&replayed)), (second.id, "second"));
+
+    let res = ann
+        .request(RequestKind::Resume(ResumeMsg {
+            token: "forged".to_string(),
+            last_seen_id: None,
+        }))
+        .await;
+    assert_eq!(error_code(&res), ErrorCode::AuthExpired);
+}
+
+#[tokio::test]
+async fn offline_queue() {
+    let (addr, _) = serve().await;
+    let (mut ann, token) = logged_in(&addr, "ann").await;
+    let mut ben = Client::connect(&addr).await;
+    ben.signup("ben").await;
+
+    // Direct msgs and mentions wait for ben, the rest of the room doesn't
+    let direct = ann.post(&token.token, Some("ben"), "psst").await;
+    let mention = ann.post(&token.token, None, "hi @ben").await;
+    ann.post(&token.token, None, "hi all").await;
+    let res = ann
+        .request(RequestKind::MsgOut(text_msg(
+            &token.token,
+            Some("nobody"),
+            "hi",
+        )))
+        .await;
+    assert_eq!(error_code(&res), ErrorCode::UserNotFound);
+
+    ben.login("ben").await;
+    let ServerRes::OfflineSummary(summary) = ben.server_res().await else {
+        panic!("expected the offline summary");
+    };
+    assert_eq!((summary.direct, summary.mentions), (1, 1));
+    assert_eq!(ben.msg_in().await.id, direct.id);
+    assert_eq!(ben.msg_in().await.id, mention.id);
+
+    // The queue is emptied once delivered
+    let mut ben = Client::connect(&addr).await;
+    ben.login("ben").await;
+    let res = ben.request(RequestKind::Logout).await;
+    assert!(matches!(res, ServerRes::Ack), "{:?}", res);
+}
+
+#[tokio::test]
+async fn permission_refusals() {
+    let (addr, shared) = serve().await;
+    let (mut ann, ann_token) = logged_in(&addr, "ann").await;
+    let (mut ben, ben_token) = logged_in(&addr, "ben").await;
+    let sent = ann.post(&ann_token.token, None, "hello").await;
+    ben.msg_in().await;
+
+    // Members can't moderate or delete msgs of others
+    let res = ben
+        .request(RequestKind::Moderate(ModerationMsg {
+            token: ben_token.token.clone(),
+            username: "ann".to_string(),
+            action: ModerationAction::Kick,
+        }))
+        .await;
+    assert_eq!(error_code(&res), ErrorCode::Forbidden);
+    let res = ben
+        .request(RequestKind::Delete(DeleteMsg {
+            token: ben_token.token.clone(),
+            id: sent.id,
+        }))
+        .await;
+    assert_eq!(error_code(&res), ErrorCode::Forbidden);
+
+    // Moderators can't moderate who is above them
+    let ben_id = shared.db.user_by_name("ben").await.unwrap().unwrap().id;
+    shared.db.set_role(ben_id, Role::Moderator).await.unwrap();
+    let res = ben
+        .request(RequestKind::Moderate(ModerationMsg {
+            token: ben_token.token.clone(),
+            username: "ann".to_string(),
+            action: ModerationAction::Mute { duration: 60 },
+        }))
+        .await;
+    assert_eq!(error_code(&res), ErrorCode::Forbidden);
+
+    // The role is read on every request, guests can't post
+    shared.db.set_role(ben_id, Role::Guest).await.unwrap();
+    let res = ben
+        .request(RequestKind::MsgOut(text_msg(&ben_token.token, None, "hi")))
+        .await;
+    assert_eq!(error_code(&res), ErrorCode::Forbidden);
+}
@@ -0,0 +1,109 @@
+use std::time::Instant;
+
+use shared_utils::{MsgType, RequestId};
+use tracing::{debug, info_span, Span};
+use tracing_subscriber::{prelude::*, reload, EnvFilter, Registry};
+
+// Changes the levels of a running server
+pub struct LogLevels {
+    handle: reload::Handle<EnvFilter, Registry>,
+    default_level: String,
+}
+
+impl LogLevels {
+    // None goes back to RUST_LOG, or the default level
+    pub fn set(&self, level: Option<&str>) -> Result<(), String> {
+        let filter = match level {
+            Some(level) => EnvFilter::try_new(level)
+                .map_err(|err| format!("invalid log level {:?}: {}", level, err))?,
+            None => env_filter(&self.default_level),
+        };
+        self.handle.reload(filter).map_err(|err| err.to_string())
+    }
+}
+
+fn env_filter(default_level: &str) -> EnvFilter {
+    EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(default_level))
+}
+
+// Levels come from RUST_LOG (the default level when unset, "chat_console=debug" for
+// everything the server logs), and LOG_FORMAT=json logs a json object per line
+pub fn init(default_level: &str) -> LogLevels {
+    let (filter, handle) = reload::Layer::new(env_filter(default_level));
+    let json = std::env::var("LOG_FORMAT").is_ok_and(|format| format == "json");
+    if json {
+        tracing_subscriber::registry()
+            .with(filter)
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .with_current_span(true)
+                    .with_span_list(true),
+            )
+            .init();
+    } else {
+        tracing_subscriber::registry()
+            .with(filter)
+            .with(tracing_subscriber::fmt::layer())
+            .init();
+    }
+    LogLevels {
+        handle,
+        default_level: default_level.to_string(),
+    }
+}
+
+// Levels of a server without a subscriber, setting them fails
+#[cfg(test)]
+pub fn detached(default_level: &str) -> LogLevels {
+    let (_, handle) = reload::Layer::new(env_filter(default_level));
+    LogLevels {
+        handle,
+        default_level: default_level.to_string(),
+    }
+}
+
+pub fn msg_type_name(msg: &MsgType) -> &'static str {
+    match msg {
+        MsgType::MsgIn(_) => "msg_in",
+        MsgType::MsgOut(_) => "msg_out",
+        MsgType::Login(_) => "login",
+        MsgType::Signup(_) => "signup",
+        MsgType::Resume(_) => "resume",
+        MsgType::Logout => "logout",
+        MsgType::Delete(_) => "delete",
+        MsgType::Moderate(_) => "moderate",
+        MsgType::ReadMarker(_) => "read_marker",
+        MsgType::Server(_) => "server",
+        MsgType::Request(_) => "request",
+        MsgType::Response(_) => "response",
+        MsgType::Ping(_) => "ping",
+        MsgType::Pong(_) => "pong",
+    }
+}
+
+// Span of a request from a client. Requests are handled inline in the connection loop, which
+// has many ways out, so the latency is logged when this is dropped
+pub struct RequestSpan {
+    pub span: Span,
+    started: Instant,
+}
+
+impl RequestSpan {
+    pub fn new(msg: &MsgType, request_id: Option<RequestId>) -> Self {
+        Self {
+            span: info_span!("request", msg_type = msg_type_name(msg), request_id),
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Drop for RequestSpan {
+    fn drop(&mut self) {
+        debug!(
+            parent: &self.span,
+            latency_us = self.started.elapsed().as_micros() as u64,
+            "request handled"
+        );
+    }
+}
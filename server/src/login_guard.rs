@@ -0,0 +1,111 @@
+use crate::{handlers::now, metrics::Metrics, storage::Storage};
+use shared_utils::{ErrorCode, ErrorMsg};
+use std::{net::IpAddr, time::Duration};
+use tracing::warn;
+
+// Seconds a failure is remembered for
+const FAILURE_WINDOW: i64 = 15 * 60;
+// An account is locked after a few failures, an address after more since users may share it
+const ACCOUNT_FAILURES_TO_LOCK: i64 = 5;
+const ADDRESS_FAILURES_TO_LOCK: i64 = 20;
+// Seconds a lockout lasts
+const LOCKOUT_TIME: i64 = 15 * 60;
+// Delay before answering an attempt, doubled with every recent failure
+const BASE_DELAY: Duration = Duration::from_millis(250);
+const MAX_DELAY: Duration = Duration::from_secs(8);
+
+// Audit events
+pub const LOGIN_FAILED: &str = "login_failed";
+pub const LOGIN_LOCKED: &str = "login_locked";
+pub const SIGNUP_FAILED: &str = "signup_failed";
+
+fn account_key(username: &str) -> String {
+    format!("account:{}", username)
+}
+
+fn address_key(ip: IpAddr) -> String {
+    format!("address:{}", ip)
+}
+
+fn keys(username: Option<&str>, ip: IpAddr) -> Vec<String> {
+    let mut keys = vec![address_key(ip)];
+    if let Some(username) = username {
+        keys.push(account_key(username));
+    }
+    keys
+}
+
+// Why the attempt is refused without checking it, if the account or address is locked
+pub async fn lockout_error(
+    db: &dyn Storage,
+    username: Option<&str>,
+    ip: IpAddr,
+) -> Result<Option<ErrorMsg>, sqlx::Error> {
+    let mut until = None;
+    for key in keys(username, ip) {
+        until = until.max(db.locked_until(&key, now()).await?);
+    }
+    let Some(until) = until else {
+        return Ok(None);
+    };
+    db.audit(LOGIN_LOCKED, username, &ip.to_string(), now())
+        .await?;
+    Ok(Some(ErrorMsg::with_detail(
+        ErrorCode::LockedOut,
+        format!(
+            "Too many failed attempts, try again in {} minutes.",
+            (until - now()) / 60 + 1
+        ),
+    )))
+}
+
+// How long to wait before answering, grows with the recent failures of the account or address
+pub async fn delay(
+    db: &dyn Storage,
+    username: Option<&str>,
+    ip: IpAddr,
+) -> Result<Duration, sqlx::Error> {
+    let mut failures = 0;
+    for key in keys(username, ip) {
+        failures = failures.max(db.failed_attempts(&key, now() - FAILURE_WINDOW).await?);
+    }
+    if failures == 0 {
+        return Ok(Duration::ZERO);
+    }
+    Ok(BASE_DELAY
+        .saturating_mul(1 << (failures - 1).min(16))
+        .min(MAX_DELAY))
+}
+
+// Audits the failure and locks the account or address when it has failed too many times
+pub async fn failure(
+    db: &dyn Storage,
+    metrics: &Metrics,
+    event: &str,
+    username: Option<&str>,
+    ip: IpAddr,
+) -> Result<(), sqlx::Error> {
+    metrics.login_failures.with_label_values(&[event]).inc();
+    db.audit(event, username, &ip.to_string(), now()).await?;
+    warn!(event, username, %ip, "failed attempt");
+    let since = now() - FAILURE_WINDOW;
+
+    let key = address_key(ip);
+    if db.add_failed_attempt(&key, now(), since).await? >= ADDRESS_FAILURES_TO_LOCK {
+        db.lock_out(&key, now() + LOCKOUT_TIME).await?;
+        warn!(%ip, "address locked out");
+    }
+    if let Some(username) = username {
+        let key = account_key(username);
+        if db.add_failed_attempt(&key, now(), since).await? >= ACCOUNT_FAILURES_TO_LOCK {
+            db.lock_out(&key, now() + LOCKOUT_TIME).await?;
+            warn!(username, "account locked out");
+        }
+    }
+    Ok(())
+}
+
+// The failures of the account are forgiven, the ones of the address stay
+pub async fn success(db: &dyn Storage, username: &str) -> Result<(), sqlx::Error> {
+    db.clear_failed_attempts(&account_key(username)).await
+}
@@ -1,25 +1,262 @@
-pub mod database;
+pub mod admin;
+pub mod config;
+pub mod error;
 pub mod handlers;
+pub mod logging;
+pub mod login_guard;
+pub mod metrics;
+pub mod migrations;
+pub mod rate_limit;
+pub mod registry;
+pub mod storage;
+pub mod tokens;
 
-use tokio::{net::TcpListener, sync::broadcast};
+use std::sync::{atomic::AtomicBool, Arc};
+use std::time::Duration;
+
+use shared_utils::{encode_msg_type, MsgType, ServerRes, ShutdownMsg};
+use tokio::{net::TcpListener, time::Instant};
+use tracing::{info, warn};
+
+const SHUTDOWN_REASON: &str = "The server is restarting.";
+// Seconds clients are told to wait before reconnecting
+const RECONNECT_AFTER: i64 = 5;
+// Time connections have to write what they have queued
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(15);
+
+// Ctrl+C, or SIGTERM on unix
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).unwrap();
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.unwrap();
+}
+
+// The config is read again on SIGHUP, connected clients stay
+#[cfg(unix)]
+async fn reload_on_hangup(settings: Arc<config::Settings>, limiter: Arc<rate_limit::RateLimiter>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = signal(SignalKind::hangup()).unwrap();
+    while hangup.recv().await.is_some() {
+        match settings.reload(&limiter) {
+            Ok(()) => info!("config reloaded"),
+            Err(err) => warn!(%err, "config not reloaded, the old one stays"),
+        }
+    }
+}
+
+const USAGE: &str = "Usage: chat-console [migrate status|up]
+       chat-console admin <command> [args]
+
+The server reads its config from CONFIG_PATH (chat-console.toml by default), again on SIGHUP";
+
+fn usage() -> ! {
+    eprintln!("{}\n\n{}", USAGE, admin::USAGE);
+    std::process::exit(2);
+}
 
 #[tokio::main]
 async fn main() {
-    let listener = TcpListener::bind("127.0.0.1:8000")
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        None => serve().await,
+        Some("migrate") => migrate(args.get(1).map(String::as_str)).await,
+        Some("admin") => admin(&args[1..]).await,
+        Some(_) => usage(),
+    }
+}
+
+// Goes through the admin socket when the server is running, to the database otherwise
+async fn admin(args: &[String]) {
+    let args = match args {
+        [command, name] if admin::takes_password(command) => {
+            let password = admin::read_password().unwrap_or_else(|err| {
+                eprintln!("Couldn't read the password: {}", err);
+                std::process::exit(1);
+            });
+            vec![command.clone(), name.clone(), password]
+        }
+        [command, ..] if admin::takes_password(command) => usage(),
+        _ => args.to_vec(),
+    };
+    let Some(command) = admin::Command::parse(&args) else {
+        usage();
+    };
+    logging::init("warn");
+    let result = match admin::request(&args).await {
+        Some(result) => result,
+        None => admin::execute_offline(&command).await,
+    };
+    match result {
+        Ok(out) if out.is_empty() => {}
+        Ok(out) => println!("{}", out),
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+// `migrate status` lists the migrations, `migrate up` applies the pending ones
+async fn migrate(command: Option<&str>) {
+    // Queries are logged at info, they would bury the output
+    logging::init("warn");
+    let db = storage::connect().await.unwrap_or_else(|err| {
+        eprintln!("Couldn't connect to the database: {}", err);
+        std::process::exit(1);
+    });
+    let result = match command {
+        Some("status") => migrations::status(db.as_ref()).await.map(|status| {
+            for (migration, applied) in status {
+                let state = if applied { "applied" } else { "pending" };
+                println!(
+                    "{:>3} {} {}",
+                    migration.version, state, migration.description
+                );
+            }
+        }),
+        Some("up") => migrations::run(db.as_ref())
+            .await
+            .map(|count| println!("{} migrations applied", count)),
+        _ => usage(),
+    };
+    db.close().await;
+    if let Err(err) = result {
+        eprintln!("Migration failed: {}", err);
+        std::process::exit(1);
+    }
+}
+
+async fn serve() {
+    let log_levels = logging::init("info");
+    let config_path = config::path();
+    let config = config::Config::load(&config_path)
+        .and_then(|config| {
+            log_levels
+                .set(config.log_level.as_deref())
+                .map_err(config::ConfigError::Invalid)?;
+            Ok(config)
+        })
+        .unwrap_or_else(|err| {
+            eprintln!("{}: {}", config_path.display(), err);
+            std::process::exit(1);
+        });
+
+    let listener = TcpListener::bind(&config.listen_addr)
         .await
-        .expect("Couldn't bind server");
+        .unwrap_or_else(|err| {
+            eprintln!("Couldn't listen on {}: {}", config.listen_addr, err);
+            std::process::exit(1);
+        });
+    info!(addr = %config.listen_addr, "listening");
 
-    // (Address, To me, encoded msg buffer)
-    let (tx, _) = broadcast::channel::<(String, bool, Vec<u8>)>(32);
+    let registry = Arc::new(registry::Registry::default());
+    let limiter = Arc::new(rate_limit::RateLimiter::new(config.rate_limits.clone()));
+    let settings = Arc::new(config::Settings::new(config_path, config, log_levels));
 
-    let db = database::connect_db().await;
-    database::create_tables(&db).await;
+    // Idle buckets are full anyway, they are dropped to keep the map small
+    let pruned_limiter = limiter.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            pruned_limiter.prune(Duration::from_secs(10 * 60));
+        }
+    });
+
+    // Drops are reported when they grow
+    let metrics = Arc::new(metrics::Metrics::new());
+    let reported_metrics = metrics.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        let mut last = (0, 0);
+        loop {
+            interval.tick().await;
+            let drops = reported_metrics.drops();
+            if drops != last {
+                warn!(
+                    dropped_msgs = drops.0,
+                    slow_disconnects = drops.1,
+                    "clients not keeping up"
+                );
+                last = drops;
+            }
+        }
+    });
+
+    let tokens = tokens::Tokens::load().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
+    let db = storage::connect()
+        .await
+        .expect("Couldn't connect to the database");
+    migrations::run(db.as_ref())
+        .await
+        .expect("Couldn't migrate the database");
+
+    let shared = handlers::Shared {
+        registry,
+        db,
+        limiter,
+        metrics,
+        maintenance: Arc::new(AtomicBool::new(false)),
+        settings,
+        tokens: Arc::new(tokens),
+    };
+
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    // Prometheus metrics are only served when METRICS_ADDR is set, e.g. "127.0.0.1:9100"
+    if let Ok(addr) = std::env::var("METRICS_ADDR") {
+        tokio::spawn(metrics::serve(
+            addr,
+            shared.registry.clone(),
+            shared.metrics.clone(),
+        ));
+    }
+    #[cfg(unix)]
+    tokio::spawn(admin::serve(shared.clone()));
+    #[cfg(unix)]
+    tokio::spawn(reload_on_hangup(
+        shared.settings.clone(),
+        shared.limiter.clone(),
+    ));
 
     loop {
-        let (socket, addr) = listener.accept().await.unwrap();
-        let tx = tx.clone();
-        let rx = tx.subscribe();
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((socket, addr)) => handlers::new_conection(socket, addr.to_string(), shared.clone()),
+                // e.g. out of file descriptors, the listener keeps working once some are freed
+                Err(err) => warn!(%err, "couldn't accept a connection"),
+            },
+            _ = &mut shutdown => break,
+        }
+    }
+
+    // No new clients while the others are told to leave
+    drop(listener);
+    info!("shutting down");
+    let res = encode_msg_type(&MsgType::Server(ServerRes::ServerShutdown(ShutdownMsg {
+        reason: SHUTDOWN_REASON.to_string(),
+        reconnect_after: RECONNECT_AFTER,
+    })));
+    shared.registry.close_all(res, &shared.metrics);
 
-        handlers::new_conection(socket, addr.to_string(), tx, rx, db.clone());
+    // Connections leave the registry once their queued msgs are written
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+    while !shared.registry.is_empty() && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(100)).await;
     }
+    #[cfg(unix)]
+    admin::remove_socket();
+    shared.db.close().await;
+    info!("server stopped");
 }
@@ -0,0 +1,166 @@
+use crate::registry::Registry;
+use prometheus::{
+    exponential_buckets, register_histogram, register_histogram_vec, register_int_counter,
+    register_int_counter_vec, register_int_gauge, Encoder, Histogram, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, TextEncoder,
+};
+use std::{
+    future::Future,
+    sync::{Arc, LazyLock},
+    time::Instant,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{info, warn};
+
+// The storages are made before Metrics, and the admin commands use them without it, so the
+// query histogram is global instead of living in Metrics
+static QUERY_SECONDS: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "chat_db_query_seconds",
+        "Time taken by database queries",
+        &["query"]
+    )
+    .unwrap()
+});
+
+// Counters shared by every connection, registered in the default prometheus registry
+pub struct Metrics {
+    // Msgs that didn't fit in the inbox or the outbound queue of a connection
+    pub dropped_msgs: IntCounter,
+    // Connections closed because the client couldn't keep up
+    pub slow_disconnects: IntCounter,
+    pub requests: IntCounterVec,
+    pub bytes_in: IntCounter,
+    pub bytes_out: IntCounter,
+    pub image_bytes: Histogram,
+    pub login_failures: IntCounterVec,
+    // Set from the connection registry on every scrape
+    connected_clients: IntGauge,
+    logged_in_users: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            dropped_msgs: register_int_counter!(
+                "chat_dropped_msgs_total",
+                "Msgs dropped for clients that fell behind"
+            )
+            .unwrap(),
+            slow_disconnects: register_int_counter!(
+                "chat_slow_disconnects_total",
+                "Connections closed because the client couldn't keep up"
+            )
+            .unwrap(),
+            requests: register_int_counter_vec!(
+                "chat_requests_total",
+                "Requests received from clients",
+                &["type"]
+            )
+            .unwrap(),
+            bytes_in: register_int_counter!("chat_bytes_in_total", "Bytes read from clients")
+                .unwrap(),
+            bytes_out: register_int_counter!("chat_bytes_out_total", "Bytes written to clients")
+                .unwrap(),
+            // 1KB to 32MB
+            image_bytes: register_histogram!(
+                "chat_image_bytes",
+                "Size of the uploaded images",
+                exponential_buckets(1024.0, 4.0, 9).unwrap()
+            )
+            .unwrap(),
+            login_failures: register_int_counter_vec!(
+                "chat_login_failures_total",
+                "Failed logins and signups",
+                &["event"]
+            )
+            .unwrap(),
+            connected_clients: register_int_gauge!(
+                "chat_connected_clients",
+                "Open client connections"
+            )
+            .unwrap(),
+            logged_in_users: register_int_gauge!(
+                "chat_logged_in_users",
+                "Users with at least a logged in connection"
+            )
+            .unwrap(),
+        }
+    }
+
+    // (dropped msgs, slow disconnects)
+    pub fn drops(&self) -> (u64, u64) {
+        (self.dropped_msgs.get(), self.slow_disconnects.get())
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Runs the query future and records how long it took
+pub async fn timed<T>(query: &'static str, future: impl Future<Output = T>) -> T {
+    let started = Instant::now();
+    let output = future.await;
+    QUERY_SECONDS
+        .with_label_values(&[query])
+        .observe(started.elapsed().as_secs_f64());
+    output
+}
+
+// Prometheus text format on GET /metrics
+pub async fn serve(addr: String, registry: Arc<Registry>, metrics: Arc<Metrics>) {
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            warn!(%err, addr, "couldn't bind the metrics endpoint");
+            return;
+        }
+    };
+    info!(addr, "serving metrics");
+    loop {
+        let Ok((socket, _)) = listener.accept().await else {
+            continue;
+        };
+        let registry = registry.clone();
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            let _ = respond(socket, &registry, &metrics).await;
+        });
+    }
+}
+
+async fn respond(
+    mut socket: TcpStream,
+    registry: &Registry,
+    metrics: &Metrics,
+) -> std::io::Result<()> {
+    // Only the request line matters
+    let mut buf = vec![0; 1024];
+    let n = socket.read(&mut buf).await?;
+    if !buf[..n].starts_with(b"GET /metrics ") {
+        return socket
+            .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+            .await;
+    }
+
+    let (connections, users) = registry.counts();
+    metrics.connected_clients.set(connections as i64);
+    metrics.logged_in_users.set(users as i64);
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    encoder.encode(&prometheus::gather(), &mut body).unwrap();
+
+    let header = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        encoder.format_type(),
+        body.len()
+    );
+    socket.write_all(header.as_bytes()).await?;
+    socket.write_all(&body).await
+}
@@ -0,0 +1,287 @@
+use crate::storage::Storage;
+use tracing::info;
+
+// The same change written for each database
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+    pub sqlite: &'static str,
+    pub postgres: &'static str,
+}
+
+// Schema changes in the order they were made. Applied ones are never edited, a change to
+// the schema is a new migration at the end
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "users",
+        sqlite: "
+          CREATE TABLE IF NOT EXISTS users (
+            id INTEGER PRIMARY KEY NOT NULL,
+            name VARCHAR(30) NOT NULL UNIQUE,
+            password VARCHAR(300) NOT NULL
+          );
+        ",
+        postgres: "
+          CREATE TABLE IF NOT EXISTS users (
+            id BIGSERIAL PRIMARY KEY NOT NULL,
+            name VARCHAR(30) NOT NULL UNIQUE,
+            password VARCHAR(300) NOT NULL
+          );
+        ",
+    },
+    Migration {
+        version: 2,
+        description: "messages",
+        sqlite: "
+          CREATE TABLE IF NOT EXISTS messages (
+            id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+            room VARCHAR(30) NOT NULL,
+            user_id INTEGER NOT NULL REFERENCES users(id),
+            username VARCHAR(30) NOT NULL,
+            recipient VARCHAR(30),
+            data BLOB NOT NULL
+          );
+        ",
+        postgres: "
+          CREATE TABLE IF NOT EXISTS messages (
+            id BIGSERIAL PRIMARY KEY NOT NULL,
+            room VARCHAR(30) NOT NULL,
+            user_id BIGINT NOT NULL REFERENCES users(id),
+            username VARCHAR(30) NOT NULL,
+            recipient VARCHAR(30),
+            data BYTEA NOT NULL
+          );
+        ",
+    },
+    Migration {
+        version: 3,
+        description: "read markers",
+        sqlite: "
+          CREATE TABLE IF NOT EXISTS read_markers (
+            user_id INTEGER NOT NULL REFERENCES users(id),
+            room VARCHAR(30) NOT NULL,
+            last_read_id INTEGER NOT NULL,
+            PRIMARY KEY (user_id, room)
+          );
+        ",
+        postgres: "
+          CREATE TABLE IF NOT EXISTS read_markers (
+            user_id BIGINT NOT NULL REFERENCES users(id),
+            room VARCHAR(30) NOT NULL,
+            last_read_id BIGINT NOT NULL,
+            PRIMARY KEY (user_id, room)
+          );
+        ",
+    },
+    Migration {
+        version: 4,
+        description: "offline queue",
+        sqlite: "
+          CREATE TABLE IF NOT EXISTS offline_queue (
+            id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+            user_id INTEGER NOT NULL REFERENCES users(id),
+            message_id INTEGER NOT NULL REFERENCES messages(id),
+            kind VARCHAR(10) NOT NULL
+          );
+        ",
+        postgres: "
+          CREATE TABLE IF NOT EXISTS offline_queue (
+            id BIGSERIAL PRIMARY KEY NOT NULL,
+            user_id BIGINT NOT NULL REFERENCES users(id),
+            message_id BIGINT NOT NULL REFERENCES messages(id),
+            kind VARCHAR(10) NOT NULL
+          );
+        ",
+    },
+    Migration {
+        version: ROLE_VERSION,
+        description: "user roles",
+        sqlite: "ALTER TABLE users ADD COLUMN role VARCHAR(10) NOT NULL DEFAULT 'member';",
+        postgres: "ALTER TABLE users ADD COLUMN role VARCHAR(10) NOT NULL DEFAULT 'member';",
+    },
+    Migration {
+        version: 6,
+        description: "bans",
+        sqlite: "
+          CREATE TABLE IF NOT EXISTS bans (
+            user_id INTEGER PRIMARY KEY NOT NULL REFERENCES users(id),
+            reason TEXT NOT NULL,
+            until INTEGER,
+            banned_by INTEGER NOT NULL REFERENCES users(id)
+          );
+        ",
+        postgres: "
+          CREATE TABLE IF NOT EXISTS bans (
+            user_id BIGINT PRIMARY KEY NOT NULL REFERENCES users(id),
+            reason TEXT NOT NULL,
+            until BIGINT,
+            banned_by BIGINT NOT NULL REFERENCES users(id)
+          );
+        ",
+    },
+    Migration {
+        version: 7,
+        description: "mutes",
+        sqlite: "
+          CREATE TABLE IF NOT EXISTS mutes (
+            user_id INTEGER PRIMARY KEY NOT NULL REFERENCES users(id),
+            until INTEGER NOT NULL,
+            muted_by INTEGER NOT NULL REFERENCES users(id)
+          );
+        ",
+        postgres: "
+          CREATE TABLE IF NOT EXISTS mutes (
+            user_id BIGINT PRIMARY KEY NOT NULL REFERENCES users(id),
+            until BIGINT NOT NULL,
+            muted_by BIGINT NOT NULL REFERENCES users(id)
+          );
+        ",
+    },
+    // Failed logins and signups per "account:name" or "address:ip" key
+    Migration {
+        version: 8,
+        description: "login attempts",
+        sqlite: "
+          CREATE TABLE IF NOT EXISTS login_attempts (
+            key VARCHAR(80) PRIMARY KEY NOT NULL,
+            failures INTEGER NOT NULL,
+            last_failure INTEGER NOT NULL,
+            locked_until INTEGER
+          );
+        ",
+        postgres: "
+          CREATE TABLE IF NOT EXISTS login_attempts (
+            key VARCHAR(80) PRIMARY KEY NOT NULL,
+            failures BIGINT NOT NULL,
+            last_failure BIGINT NOT NULL,
+            locked_until BIGINT
+          );
+        ",
+    },
+    Migration {
+        version: 9,
+        description: "audit log",
+        sqlite: "
+          CREATE TABLE IF NOT EXISTS audit_log (
+            id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+            event VARCHAR(20) NOT NULL,
+            username VARCHAR(30),
+            address VARCHAR(60) NOT NULL,
+            created_at INTEGER NOT NULL
+          );
+        ",
+        postgres: "
+          CREATE TABLE IF NOT EXISTS audit_log (
+            id BIGSERIAL PRIMARY KEY NOT NULL,
+            event VARCHAR(20) NOT NULL,
+            username VARCHAR(30),
+            address VARCHAR(60) NOT NULL,
+            created_at BIGINT NOT NULL
+          );
+        ",
+    },
+    // Tokens carry the version, bumping it revokes them. SQLite reuses the id of the newest
+    // row once it's deleted, so user ids come from a counter that never goes back
+    Migration {
+        version: 10,
+        description: "token versions",
+        sqlite: "
+          ALTER TABLE users ADD COLUMN token_version INTEGER NOT NULL DEFAULT 0;
+          CREATE TABLE IF NOT EXISTS user_ids (last_id INTEGER NOT NULL);
+          INSERT INTO user_ids (last_id) SELECT COALESCE(MAX(id), 0) FROM users;
+        ",
+        postgres: "ALTER TABLE users ADD COLUMN token_version BIGINT NOT NULL DEFAULT 0;",
+    },
+    // Bans from the admin console have no moderator
+    Migration {
+        version: 11,
+        description: "console bans",
+        sqlite: "
+          CREATE TABLE bans_new (
+            user_id INTEGER PRIMARY KEY NOT NULL REFERENCES users(id),
+            reason TEXT NOT NULL,
+            until INTEGER,
+            banned_by INTEGER REFERENCES users(id)
+          );
+          INSERT INTO bans_new (user_id, reason, until, banned_by)
+            SELECT user_id, reason, until, banned_by FROM bans;
+          DROP TABLE bans;
+          ALTER TABLE bans_new RENAME TO bans;
+        ",
+        postgres: "ALTER TABLE bans ALTER COLUMN banned_by DROP NOT NULL;",
+    },
+    // Unread counts and history read the msgs of a room by id
+    Migration {
+        version: 12,
+        description: "messages by room",
+        sqlite: "CREATE INDEX IF NOT EXISTS messages_room_id ON messages (room, id);",
+        postgres: "CREATE INDEX IF NOT EXISTS messages_room_id ON messages (room, id);",
+    },
+];
+
+// Databases made before migrations may have the role column already
+pub const ROLE_VERSION: i64 = 5;
+
+#[derive(Debug)]
+pub enum MigrateError {
+    Database(sqlx::Error),
+    // The database was migrated by a newer server
+    UnknownVersion(i64),
+}
+
+impl std::fmt::Display for MigrateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MigrateError::Database(err) => write!(f, "database error: {}", err),
+            MigrateError::UnknownVersion(version) => write!(
+                f,
+                "the database is at schema version {}, newer than this server knows",
+                version
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MigrateError {}
+
+impl From<sqlx::Error> for MigrateError {
+    fn from(err: sqlx::Error) -> Self {
+        MigrateError::Database(err)
+    }
+}
+
+// Each migration with whether it's applied
+pub async fn status(db: &dyn Storage) -> Result<Vec<(&'static Migration, bool)>, MigrateError> {
+    let applied = db.applied_migrations().await?;
+    if let Some(unknown) = applied.iter().find(|version| {
+        !MIGRATIONS
+            .iter()
+            .any(|migration| migration.version == **version)
+    }) {
+        return Err(MigrateError::UnknownVersion(*unknown));
+    }
+    Ok(MIGRATIONS
+        .iter()
+        .map(|migration| (migration, applied.contains(&migration.version)))
+        .collect())
+}
+
+// Applies the pending migrations in order, each one in its own transaction. Returns how
+// many were applied
+pub async fn run(db: &dyn Storage) -> Result<usize, MigrateError> {
+    let mut count = 0;
+    for (migration, applied) in status(db).await? {
+        if applied {
+            continue;
+        }
+        db.apply_migration(migration).await?;
+        info!(
+            version = migration.version,
+            description = migration.description,
+            "migration applied"
+        );
+        count += 1;
+    }
+    Ok(count)
+}
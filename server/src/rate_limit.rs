@@ -0,0 +1,225 @@
+use serde::Deserialize;
+use shared_utils::{MsgDataType, MsgType};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+// Rate limited requests
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Action {
+    Message,
+    Image,
+    Login,
+    Signup,
+}
+
+impl Action {
+    pub fn of(msg: &MsgType) -> Option<Action> {
+        match msg {
+            MsgType::MsgOut(msg) => match msg.data {
+                MsgDataType::Text(_) => Some(Action::Message),
+                MsgDataType::Image(_) => Some(Action::Image),
+            },
+            MsgType::Login(_) | MsgType::Resume(_) => Some(Action::Login),
+            MsgType::Signup(_) => Some(Action::Signup),
+            _ => None,
+        }
+    }
+}
+
+// Burst size and sustained rate of a bucket
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Limit {
+    pub burst: f64,
+    pub per_second: f64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimits {
+    pub message: Limit,
+    pub image: Limit,
+    pub login: Limit,
+    pub signup: Limit,
+    // The same user or address can spread requests over a few connections
+    pub shared_factor: f64,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            message: Limit {
+                burst: 10.0,
+                per_second: 2.0,
+            },
+            image: Limit {
+                burst: 3.0,
+                per_second: 0.1,
+            },
+            login: Limit {
+                burst: 5.0,
+                per_second: 0.1,
+            },
+            signup: Limit {
+                burst: 2.0,
+                per_second: 0.01,
+            },
+            shared_factor: 3.0,
+        }
+    }
+}
+
+impl RateLimits {
+    fn limit(&self, action: Action) -> Limit {
+        match action {
+            Action::Message => self.message,
+            Action::Image => self.image,
+            Action::Login => self.login,
+            Action::Signup => self.signup,
+        }
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, limit: Limit, now: Instant) {
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst);
+        self.last = now;
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Key {
+    Connection(String),
+    User(i64),
+    Ip(IpAddr),
+}
+
+pub struct RateLimiter {
+    limits: RwLock<RateLimits>,
+    buckets: Mutex<HashMap<(Key, Action), TokenBucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits: RwLock::new(limits),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Take a token from the buckets of the connection, its address and its user. Nothing is
+    // taken unless all of them have one
+    pub fn check(&self, action: Action, connection: &str, ip: IpAddr, user: Option<i64>) -> bool {
+        let (limit, shared) = {
+            let limits = self.limits.read().unwrap();
+            let limit = limits.limit(action);
+            let shared = Limit {
+                burst: limit.burst * limits.shared_factor,
+                per_second: limit.per_second * limits.shared_factor,
+            };
+            (limit, shared)
+        };
+        let mut keys = vec![
+            (Key::Connection(connection.to_string()), limit),
+            (Key::Ip(ip), shared),
+        ];
+        if let Some(user) = user {
+            keys.push((Key::User(user), shared));
+        }
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        for (key, limit) in &keys {
+            let bucket = buckets.entry((key.clone(), action)).or_insert(TokenBucket {
+                tokens: limit.burst,
+                last: now,
+            });
+            bucket.refill(*limit, now);
+            if bucket.tokens < 1.0 {
+                return false;
+            }
+        }
+        for (key, _) in keys {
+            if let Some(bucket) = buckets.get_mut(&(key, action)) {
+                bucket.tokens -= 1.0;
+            }
+        }
+        true
+    }
+
+    // Buckets keep their tokens, they fill up to the new bursts from now on
+    pub fn set_limits(&self, limits: RateLimits) {
+        *self.limits.write().unwrap() = limits;
+    }
+
+    pub fn forget_connection(&self, connection: &str) {
+        self.buckets
+            .lock()
+            .unwrap()
+            .retain(|(key, _), _| !matches!(key, Key::Connection(addr) if addr == connection));
+    }
+
+    // Drop the buckets that have been idle long enough to be full again
+    pub fn prune(&self, idle: Duration) {
+        let now = Instant::now();
+        self.buckets
+            .lock()
+            .unwrap()
+            .retain(|_, bucket| now.duration_since(bucket.last) < idle);
+    }
+}
+
+// Warnings before a connection is throttled, and then disconnected
+pub const STRIKES_TO_THROTTLE: u32 = 5;
+pub const STRIKES_TO_DISCONNECT: u32 = 20;
+pub const THROTTLE_TIME: Duration = Duration::from_secs(30);
+// Strikes are forgotten after this long without new ones
+pub const STRIKE_RESET_TIME: Duration = Duration::from_secs(60);
+
+// Abuse tracking of a single connection
+#[derive(Default)]
+pub struct Strikes {
+    count: u32,
+    last: Option<Instant>,
+    throttled_until: Option<Instant>,
+}
+
+pub enum Penalty {
+    Warn,
+    Throttle,
+    Disconnect,
+}
+
+impl Strikes {
+    pub fn is_throttled(&self) -> bool {
+        matches!(self.throttled_until, Some(until) if until > Instant::now())
+    }
+
+    pub fn strike(&mut self) -> Penalty {
+        let now = Instant::now();
+        if matches!(self.last, Some(last) if now.duration_since(last) > STRIKE_RESET_TIME) {
+            self.count = 0;
+        }
+        self.count += 1;
+        self.last = Some(now);
+
+        if self.count >= STRIKES_TO_DISCONNECT {
+            Penalty::Disconnect
+        } else if self.count.is_multiple_of(STRIKES_TO_THROTTLE) {
+            self.throttled_until = Some(now + THROTTLE_TIME);
+            Penalty::Throttle
+        } else {
+            Penalty::Warn
+        }
+    }
+}
@@ -0,0 +1,240 @@
+use crate::metrics::Metrics;
+use shared_utils::DEFAULT_ROOM;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+pub type ConnectionId = u64;
+
+// Time a kicked user has to wait before logging in again
+const KICK_COOLDOWN: Duration = Duration::from_secs(60);
+
+// What a connection task is sent by the others
+#[derive(Debug)]
+pub enum Delivery {
+    Msg(Vec<u8>),
+    // Written right before the connection is closed
+    Close(Vec<u8>),
+}
+
+struct Connection {
+    inbox: mpsc::Sender<Delivery>,
+    addr: String,
+    connected_at: Instant,
+    // Id and name of the logged in user
+    user: Option<(i64, String)>,
+    rooms: HashSet<String>,
+}
+
+// What the admin socket shows of a connection
+pub struct ConnectionInfo {
+    pub id: ConnectionId,
+    pub addr: String,
+    pub username: Option<String>,
+    pub rooms: Vec<String>,
+    pub connected_for: Duration,
+}
+
+// Every open connection, so msgs are handed only to the ones they are for
+#[derive(Default)]
+pub struct Registry {
+    next_id: AtomicU64,
+    connections: Mutex<HashMap<ConnectionId, Connection>>,
+    // Ids of the users kicked out lately and when they can come back
+    cooldowns: Mutex<HashMap<i64, Instant>>,
+}
+
+impl Registry {
+    pub fn register(&self, inbox: mpsc::Sender<Delivery>, addr: &str) -> ConnectionId {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.connections.lock().unwrap().insert(
+            id,
+            Connection {
+                inbox,
+                addr: addr.to_string(),
+                connected_at: Instant::now(),
+                user: None,
+                rooms: HashSet::new(),
+            },
+        );
+        id
+    }
+
+    pub fn unregister(&self, id: ConnectionId) {
+        self.connections.lock().unwrap().remove(&id);
+    }
+
+    // Logged in users are in the default room
+    pub fn login(&self, id: ConnectionId, user_id: i64, username: &str) {
+        if let Some(connection) = self.connections.lock().unwrap().get_mut(&id) {
+            connection.user = Some((user_id, username.to_string()));
+            connection.rooms = HashSet::from([DEFAULT_ROOM.to_string()]);
+        }
+    }
+
+    pub fn logout(&self, id: ConnectionId) {
+        if let Some(connection) = self.connections.lock().unwrap().get_mut(&id) {
+            connection.user = None;
+            connection.rooms.clear();
+        }
+    }
+
+    pub fn join(&self, id: ConnectionId, room: &str) {
+        if let Some(connection) = self.connections.lock().unwrap().get_mut(&id) {
+            connection.rooms.insert(room.to_string());
+        }
+    }
+
+    pub fn is_online(&self, username: &str) -> bool {
+        self.connections
+            .lock()
+            .unwrap()
+            .values()
+            .any(|connection| matches!(&connection.user, Some((_, name)) if name == username))
+    }
+
+    pub fn to_room(&self, room: &str, msg: Vec<u8>, metrics: &Metrics) {
+        self.deliver(
+            |connection| connection.rooms.contains(room),
+            || Delivery::Msg(msg.clone()),
+            metrics,
+        );
+    }
+
+    // Every session of the users
+    pub fn to_users(&self, usernames: &[String], msg: Vec<u8>, metrics: &Metrics) {
+        self.deliver(
+            |connection| matches!(&connection.user, Some((_, name)) if usernames.contains(name)),
+            || Delivery::Msg(msg.clone()),
+            metrics,
+        );
+    }
+
+    pub fn to_logged(&self, msg: Vec<u8>, metrics: &Metrics) {
+        self.deliver(
+            |connection| connection.user.is_some(),
+            || Delivery::Msg(msg.clone()),
+            metrics,
+        );
+    }
+
+    // Closes every session of the user after writing the msg
+    pub fn kick(&self, username: &str, msg: Vec<u8>, metrics: &Metrics) {
+        self.deliver(
+            |connection| matches!(&connection.user, Some((_, name)) if name == username),
+            || Delivery::Close(msg.clone()),
+            metrics,
+        );
+    }
+
+    // Keeps the user from logging in or resuming a session for a while, so a kicked client
+    // can't come right back
+    pub fn cool_down(&self, user_id: i64) {
+        let now = Instant::now();
+        let mut cooldowns = self.cooldowns.lock().unwrap();
+        cooldowns.retain(|_, until| *until > now);
+        cooldowns.insert(user_id, now + KICK_COOLDOWN);
+    }
+
+    // Time left until the user can login again, if kicked lately
+    pub fn cooldown_left(&self, user_id: i64) -> Option<Duration> {
+        let until = *self.cooldowns.lock().unwrap().get(&user_id)?;
+        let left = until.saturating_duration_since(Instant::now());
+        (!left.is_zero()).then_some(left)
+    }
+
+    // Id of the user logged in through the connection
+    pub fn user_id(&self, id: ConnectionId) -> Option<i64> {
+        let connections = self.connections.lock().unwrap();
+        connections
+            .get(&id)?
+            .user
+            .as_ref()
+            .map(|(user_id, _)| *user_id)
+    }
+
+    // Closes every connection after writing the msg
+    pub fn close_all(&self, msg: Vec<u8>, metrics: &Metrics) {
+        self.deliver(|_| true, || Delivery::Close(msg.clone()), metrics);
+    }
+
+    // (open connections, distinct logged in users)
+    pub fn counts(&self) -> (usize, usize) {
+        let connections = self.connections.lock().unwrap();
+        let users: HashSet<i64> = connections
+            .values()
+            .filter_map(|connection| connection.user.as_ref().map(|(id, _)| *id))
+            .collect();
+        (connections.len(), users.len())
+    }
+
+    // Closes the connection after writing the msg, false when there is no such connection
+    pub fn close(&self, id: ConnectionId, msg: Vec<u8>, metrics: &Metrics) -> bool {
+        let mut connections = self.connections.lock().unwrap();
+        let Some(connection) = connections.get(&id) else {
+            return false;
+        };
+        if let Err(TrySendError::Full(_)) = connection.inbox.try_send(Delivery::Close(msg)) {
+            metrics.dropped_msgs.inc();
+            connections.remove(&id);
+        }
+        true
+    }
+
+    // Every open connection by id
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        let connections = self.connections.lock().unwrap();
+        let mut infos: Vec<ConnectionInfo> = connections
+            .iter()
+            .map(|(id, connection)| {
+                let mut rooms: Vec<String> = connection.rooms.iter().cloned().collect();
+                rooms.sort();
+                ConnectionInfo {
+                    id: *id,
+                    addr: connection.addr.clone(),
+                    username: connection.user.as_ref().map(|(_, name)| name.clone()),
+                    rooms,
+                    connected_for: connection.connected_at.elapsed(),
+                }
+            })
+            .collect();
+        infos.sort_by_key(|info| info.id);
+        infos
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.connections.lock().unwrap().is_empty()
+    }
+
+    // A connection whose inbox is full is removed. Its task sees the inbox closed once it
+    // catches up with what's in it and ends the connection, the client resumes the session
+    // and gets the missed msgs from the database
+    fn deliver(
+        &self,
+        to: impl Fn(&Connection) -> bool,
+        delivery: impl Fn() -> Delivery,
+        metrics: &Metrics,
+    ) {
+        let mut connections = self.connections.lock().unwrap();
+        let mut behind = Vec::new();
+        for (id, connection) in connections.iter().filter(|(_, connection)| to(connection)) {
+            match connection.inbox.try_send(delivery()) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    metrics.dropped_msgs.inc();
+                    behind.push(*id);
+                }
+                Err(TrySendError::Closed(_)) => {}
+            }
+        }
+        for id in behind {
+            connections.remove(&id);
+        }
+    }
+}
@@ -0,0 +1,545 @@
+use super::{
+    Ban, MessageRow, NewMessage, Stats, Storage, StorageFuture, User, UserSummary, REPLAY_LIMIT,
+};
+use crate::migrations::Migration;
+use shared_utils::{Role, ServerMsg, UnreadCount};
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    pin::Pin,
+    sync::Mutex,
+};
+
+struct StoredUser {
+    id: i64,
+    name: String,
+    password: String,
+    role: Role,
+    token_version: i64,
+}
+
+struct StoredMsg {
+    room: String,
+    user_id: i64,
+    username: String,
+    recipient: Option<String>,
+    data: Vec<u8>,
+}
+
+impl StoredMsg {
+    fn to_server_msg(&self, id: i64) -> Option<ServerMsg> {
+        MessageRow {
+            id,
+            username: self.username.clone(),
+            room: self.room.clone(),
+            recipient: self.recipient.clone(),
+            data: self.data.clone(),
+        }
+        .into_server_msg()
+    }
+}
+
+struct Queued {
+    user_id: i64,
+    message_id: i64,
+    kind: String,
+}
+
+struct Attempts {
+    failures: i64,
+    last_failure: i64,
+    locked_until: Option<i64>,
+}
+
+#[derive(Default)]
+struct Tables {
+    users: Vec<StoredUser>,
+    // By id, so they are in the order they were sent
+    messages: BTreeMap<i64, StoredMsg>,
+    next_message_id: i64,
+    // Ids of deleted users aren't given again
+    last_user_id: i64,
+    read_markers: HashMap<(i64, String), i64>,
+    offline_queue: Vec<Queued>,
+    // User id to reason, until and who banned them, none for the admin console
+    bans: HashMap<i64, (String, Option<i64>, Option<i64>)>,
+    // User id to until and who muted them
+    mutes: HashMap<i64, (i64, i64)>,
+    login_attempts: HashMap<String, Attempts>,
+    // Event, username, address and time
+    audit_log: Vec<(String, Option<String>, String, i64)>,
+    migrations: Vec<i64>,
+}
+
+impl Tables {
+    fn user_name(&self, id: i64) -> Option<&str> {
+        self.users
+            .iter()
+            .find(|user| user.id == id)
+            .map(|user| user.name.as_str())
+    }
+
+    // Deletes the matching msgs with their queue entries. Returns how many
+    fn delete_messages(&mut self, matches: impl Fn(&StoredMsg) -> bool) -> u64 {
+        let before = self.messages.len();
+        self.messages.retain(|_, msg| !matches(msg));
+        let messages = &self.messages;
+        self.offline_queue
+            .retain(|queued| messages.contains_key(&queued.message_id));
+        (before - self.messages.len()) as u64
+    }
+
+    fn failed_attempts(&self, key: &str, since: i64) -> i64 {
+        match self.login_attempts.get(key) {
+            Some(attempts) if attempts.last_failure > since => attempts.failures,
+            _ => 0,
+        }
+    }
+}
+
+// Everything is kept in memory and lost when the server stops, for tests and trying the
+// server out. Behaves like the sql storages
+#[derive(Default)]
+pub struct MemoryStorage {
+    tables: Mutex<Tables>,
+}
+
+fn ready<T: Send + 'static>(value: T) -> StorageFuture<'static, T> {
+    Box::pin(std::future::ready(Ok(value)))
+}
+
+impl Storage for MemoryStorage {
+    fn user_by_name<'a>(&'a self, name: &'a str) -> StorageFuture<'a, Option<User>> {
+        let tables = self.tables.lock().unwrap();
+        ready(
+            tables
+                .users
+                .iter()
+                .find(|user| user.name == name)
+                .map(|user| User {
+                    id: user.id,
+                    name: user.name.clone(),
+                    password: user.password.clone(),
+                }),
+        )
+    }
+
+    fn user_by_id(&self, id: i64) -> StorageFuture<'_, Option<User>> {
+        let tables = self.tables.lock().unwrap();
+        ready(
+            tables
+                .users
+                .iter()
+                .find(|user| user.id == id)
+                .map(|user| User {
+                    id: user.id,
+                    name: user.name.clone(),
+                    password: user.password.clone(),
+                }),
+        )
+    }
+
+    fn create_user<'a>(&'a self, name: &'a str, password: &'a str) -> StorageFuture<'a, bool> {
+        let mut tables = self.tables.lock().unwrap();
+        if tables.users.iter().any(|user| user.name == name) {
+            return ready(false);
+        }
+        let role = if tables.users.is_empty() {
+            Role::Owner
+        } else {
+            Role::Member
+        };
+        tables.last_user_id += 1;
+        let id = tables.last_user_id;
+        tables.users.push(StoredUser {
+            id,
+            name: name.to_string(),
+            password: password.to_string(),
+            role,
+            token_version: 0,
+        });
+        ready(true)
+    }
+
+    fn token_version(&self, user_id: i64) -> StorageFuture<'_, Option<i64>> {
+        let tables = self.tables.lock().unwrap();
+        ready(
+            tables
+                .users
+                .iter()
+                .find(|user| user.id == user_id)
+                .map(|user| user.token_version),
+        )
+    }
+
+    fn user_role(&self, user_id: i64) -> StorageFuture<'_, Role> {
+        let tables = self.tables.lock().unwrap();
+        ready(
+            tables
+                .users
+                .iter()
+                .find(|user| user.id == user_id)
+                .map(|user| user.role)
+                .unwrap_or(Role::Guest),
+        )
+    }
+
+    fn list_users(&self) -> StorageFuture<'_, Vec<UserSummary>> {
+        let tables = self.tables.lock().unwrap();
+        ready(
+            tables
+                .users
+                .iter()
+                .map(|user| UserSummary {
+                    id: user.id,
+                    name: user.name.clone(),
+                    role: user.role,
+                })
+                .collect(),
+        )
+    }
+
+    fn delete_user(&self, user_id: i64) -> StorageFuture<'_, ()> {
+        let mut tables = self.tables.lock().unwrap();
+        let tables = &mut *tables;
+        tables.messages.retain(|_, msg| msg.user_id != user_id);
+        let messages = &tables.messages;
+        tables.offline_queue.retain(|queued| {
+            queued.user_id != user_id && messages.contains_key(&queued.message_id)
+        });
+        tables.read_markers.retain(|(id, _), _| *id != user_id);
+        tables
+            .bans
+            .retain(|id, (_, _, banned_by)| *id != user_id && *banned_by != Some(user_id));
+        tables
+            .mutes
+            .retain(|id, (_, muted_by)| *id != user_id && *muted_by != user_id);
+        tables.users.retain(|user| user.id != user_id);
+        ready(())
+    }
+
+    fn set_password<'a>(&'a self, user_id: i64, password: &'a str) -> StorageFuture<'a, ()> {
+        let mut tables = self.tables.lock().unwrap();
+        if let Some(user) = tables.users.iter_mut().find(|user| user.id == user_id) {
+            user.password = password.to_string();
+            user.token_version += 1;
+        }
+        ready(())
+    }
+
+    fn set_role(&self, user_id: i64, role: Role) -> StorageFuture<'_, ()> {
+        let mut tables = self.tables.lock().unwrap();
+        if let Some(user) = tables.users.iter_mut().find(|user| user.id == user_id) {
+            user.role = role;
+        }
+        ready(())
+    }
+
+    fn active_ban(&self, user_id: i64, now: i64) -> StorageFuture<'_, Option<Ban>> {
+        let tables = self.tables.lock().unwrap();
+        ready(
+            tables
+                .bans
+                .get(&user_id)
+                .filter(|(_, until, _)| until.is_none_or(|until| until > now))
+                .map(|(reason, until, _)| Ban {
+                    reason: reason.clone(),
+                    until: *until,
+                }),
+        )
+    }
+
+    fn ban_user<'a>(
+        &'a self,
+        user_id: i64,
+        reason: &'a str,
+        until: Option<i64>,
+        banned_by: Option<i64>,
+    ) -> StorageFuture<'a, ()> {
+        let mut tables = self.tables.lock().unwrap();
+        tables
+            .bans
+            .insert(user_id, (reason.to_string(), until, banned_by));
+        ready(())
+    }
+
+    fn unban_user(&self, user_id: i64) -> StorageFuture<'_, bool> {
+        let mut tables = self.tables.lock().unwrap();
+        ready(tables.bans.remove(&user_id).is_some())
+    }
+
+    fn muted_until(&self, user_id: i64, now: i64) -> StorageFuture<'_, Option<i64>> {
+        let tables = self.tables.lock().unwrap();
+        ready(
+            tables
+                .mutes
+                .get(&user_id)
+                .map(|(until, _)| *until)
+                .filter(|until| *until > now),
+        )
+    }
+
+    fn mute_user(&self, user_id: i64, until: i64, muted_by: i64) -> StorageFuture<'_, ()> {
+        let mut tables = self.tables.lock().unwrap();
+        tables.mutes.insert(user_id, (until, muted_by));
+        ready(())
+    }
+
+    fn insert_message<'a>(&'a self, msg: NewMessage<'a>) -> StorageFuture<'a, i64> {
+        let mut tables = self.tables.lock().unwrap();
+        tables.next_message_id += 1;
+        let id = tables.next_message_id;
+        tables.messages.insert(
+            id,
+            StoredMsg {
+                room: msg.room.to_string(),
+                user_id: msg.user_id,
+                username: msg.username.to_string(),
+                recipient: msg.recipient.map(str::to_string),
+                data: msg.data,
+            },
+        );
+        ready(id)
+    }
+
+    fn message_owner(&self, message_id: i64) -> StorageFuture<'_, Option<i64>> {
+        let tables = self.tables.lock().unwrap();
+        ready(tables.messages.get(&message_id).map(|msg| msg.user_id))
+    }
+
+    fn delete_message(&self, message_id: i64) -> StorageFuture<'_, bool> {
+        let mut tables = self.tables.lock().unwrap();
+        tables
+            .offline_queue
+            .retain(|queued| queued.message_id != message_id);
+        ready(tables.messages.remove(&message_id).is_some())
+    }
+
+    fn delete_room_messages<'a>(&'a self, room: &'a str) -> StorageFuture<'a, u64> {
+        let mut tables = self.tables.lock().unwrap();
+        ready(tables.delete_messages(|msg| msg.recipient.is_none() && msg.room == room))
+    }
+
+    fn delete_user_messages(&self, user_id: i64) -> StorageFuture<'_, u64> {
+        let mut tables = self.tables.lock().unwrap();
+        ready(tables.delete_messages(|msg| msg.user_id == user_id))
+    }
+
+    fn unread_counts(&self, user_id: i64) -> StorageFuture<'_, Vec<UnreadCount>> {
+        let tables = self.tables.lock().unwrap();
+        let name = tables.user_name(user_id);
+        // Room to count, last read id and newest id, sorted by room like the sql storages
+        let mut counts: BTreeMap<String, (i64, i64, i64)> = BTreeMap::new();
+        for (id, msg) in &tables.messages {
+            if msg.user_id == user_id {
+                continue;
+            }
+            let room = match &msg.recipient {
+                None => msg.room.clone(),
+                Some(recipient) if Some(recipient.as_str()) == name => {
+                    format!("@{}", msg.username)
+                }
+                Some(_) => continue,
+            };
+            let last_read_id = tables
+                .read_markers
+                .get(&(user_id, room.clone()))
+                .copied()
+                .unwrap_or(0);
+            if *id > last_read_id {
+                let count = counts.entry(room).or_insert((0, last_read_id, 0));
+                count.0 += 1;
+                count.2 = count.2.max(*id);
+            }
+        }
+        ready(
+            counts
+                .into_iter()
+                .map(|(room, (count, last_read_id, newest_id))| UnreadCount {
+                    room,
+                    count,
+                    last_read_id,
+                    newest_id,
+                })
+                .collect(),
+        )
+    }
+
+    fn set_read_marker<'a>(
+        &'a self,
+        user_id: i64,
+        room: &'a str,
+        last_read_id: i64,
+    ) -> StorageFuture<'a, ()> {
+        let mut tables = self.tables.lock().unwrap();
+        let marker = tables
+            .read_markers
+            .entry((user_id, room.to_string()))
+            .or_insert(last_read_id);
+        *marker = (*marker).max(last_read_id);
+        ready(())
+    }
+
+    fn queue_offline<'a>(
+        &'a self,
+        username: &'a str,
+        message_id: i64,
+        kind: &'a str,
+    ) -> StorageFuture<'a, ()> {
+        let mut tables = self.tables.lock().unwrap();
+        if let Some(user_id) = tables
+            .users
+            .iter()
+            .find(|user| user.name == username)
+            .map(|user| user.id)
+        {
+            tables.offline_queue.push(Queued {
+                user_id,
+                message_id,
+                kind: kind.to_string(),
+            });
+        }
+        ready(())
+    }
+
+    fn messages_since(&self, user_id: i64, last_seen_id: i64) -> StorageFuture<'_, Vec<ServerMsg>> {
+        let tables = self.tables.lock().unwrap();
+        let name = tables.user_name(user_id);
+        let mut msgs: Vec<ServerMsg> = tables
+            .messages
+            .range(last_seen_id + 1..)
+            .rev()
+            .filter(|(_, msg)| {
+                msg.recipient.is_none()
+                    || msg.user_id == user_id
+                    || msg.recipient.as_deref() == name
+            })
+            .take(REPLAY_LIMIT as usize)
+            .filter_map(|(id, msg)| msg.to_server_msg(*id))
+            .collect();
+        msgs.reverse();
+        ready(msgs)
+    }
+
+    fn take_offline_queue(&self, user_id: i64) -> StorageFuture<'_, Vec<(String, ServerMsg)>> {
+        let mut tables = self.tables.lock().unwrap();
+        let (taken, kept) = std::mem::take(&mut tables.offline_queue)
+            .into_iter()
+            .partition(|queued| queued.user_id == user_id);
+        tables.offline_queue = kept;
+        let mut queued: Vec<(String, ServerMsg)> = taken
+            .into_iter()
+            .filter_map(|queued: Queued| {
+                let msg = tables.messages.get(&queued.message_id)?;
+                Some((queued.kind, msg.to_server_msg(queued.message_id)?))
+            })
+            .collect();
+        queued.sort_by_key(|(_, msg)| msg.id);
+        ready(queued)
+    }
+
+    fn failed_attempts<'a>(&'a self, key: &'a str, since: i64) -> StorageFuture<'a, i64> {
+        let tables = self.tables.lock().unwrap();
+        ready(tables.failed_attempts(key, since))
+    }
+
+    fn add_failed_attempt<'a>(
+        &'a self,
+        key: &'a str,
+        now: i64,
+        since: i64,
+    ) -> StorageFuture<'a, i64> {
+        let mut tables = self.tables.lock().unwrap();
+        let attempts = tables
+            .login_attempts
+            .entry(key.to_string())
+            .or_insert(Attempts {
+                failures: 0,
+                last_failure: 0,
+                locked_until: None,
+            });
+        attempts.failures = if attempts.last_failure > since {
+            attempts.failures + 1
+        } else {
+            1
+        };
+        attempts.last_failure = now;
+        ready(tables.failed_attempts(key, since))
+    }
+
+    fn clear_failed_attempts<'a>(&'a self, key: &'a str) -> StorageFuture<'a, ()> {
+        let mut tables = self.tables.lock().unwrap();
+        tables.login_attempts.remove(key);
+        ready(())
+    }
+
+    fn lock_out<'a>(&'a self, key: &'a str, until: i64) -> StorageFuture<'a, ()> {
+        let mut tables = self.tables.lock().unwrap();
+        if let Some(attempts) = tables.login_attempts.get_mut(key) {
+            attempts.locked_until = Some(until);
+        }
+        ready(())
+    }
+
+    fn locked_until<'a>(&'a self, key: &'a str, now: i64) -> StorageFuture<'a, Option<i64>> {
+        let tables = self.tables.lock().unwrap();
+        ready(
+            tables
+                .login_attempts
+                .get(key)
+                .and_then(|attempts| attempts.locked_until)
+                .filter(|until| *until > now),
+        )
+    }
+
+    fn audit<'a>(
+        &'a self,
+        event: &'a str,
+        username: Option<&'a str>,
+        address: &'a str,
+        now: i64,
+    ) -> StorageFuture<'a, ()> {
+        let mut tables = self.tables.lock().unwrap();
+        tables.audit_log.push((
+            event.to_string(),
+            username.map(str::to_string),
+            address.to_string(),
+            now,
+        ));
+        ready(())
+    }
+
+    fn stats(&self, now: i64) -> StorageFuture<'_, Stats> {
+        let tables = self.tables.lock().unwrap();
+        ready(Stats {
+            users: tables.users.len() as i64,
+            messages: tables.messages.len() as i64,
+            active_bans: tables
+                .bans
+                .values()
+                .filter(|(_, until, _)| until.is_none_or(|until| until > now))
+                .count() as i64,
+            active_mutes: tables
+                .mutes
+                .values()
+                .filter(|(until, _)| *until > now)
+                .count() as i64,
+        })
+    }
+
+    fn applied_migrations(&self) -> StorageFuture<'_, Vec<i64>> {
+        let tables = self.tables.lock().unwrap();
+        ready(tables.migrations.clone())
+    }
+
+    // The tables are structs already, only the version is recorded
+    fn apply_migration(&self, migration: &'static Migration) -> StorageFuture<'_, ()> {
+        let mut tables = self.tables.lock().unwrap();
+        tables.migrations.push(migration.version);
+        ready(())
+    }
+
+    fn close(&self) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(std::future::ready(()))
+    }
+}
@@ -0,0 +1,627 @@
+use super::{
+    Ban, MessageRow, NewMessage, QueuedRow, Stats, Storage, StorageFuture, UnreadRow, User,
+    UserRow, UserSummary, REPLAY_LIMIT,
+};
+use crate::handlers::now;
+use crate::metrics::timed;
+use crate::migrations::{Migration, MIGRATIONS, ROLE_VERSION};
+use shared_utils::{Role, ServerMsg, UnreadCount};
+use sqlx::{migrate::MigrateDatabase, Executor, Pool, Sqlite, SqlitePool};
+use std::{future::Future, pin::Pin};
+use tracing::info;
+
+const SCHEMA_VERSION_TABLE: &str = "
+  CREATE TABLE IF NOT EXISTS schema_version (
+    version INTEGER PRIMARY KEY NOT NULL,
+    description TEXT NOT NULL,
+    applied_at INTEGER NOT NULL
+  );
+";
+
+pub struct SqliteStorage {
+    db: Pool<Sqlite>,
+}
+
+impl SqliteStorage {
+    // The file is created when missing
+    pub async fn connect(url: &str) -> Result<Self, sqlx::Error> {
+        if !Sqlite::database_exists(url).await.unwrap_or(false) {
+            Sqlite::create_database(url).await?;
+            info!("database created");
+        }
+        Ok(SqliteStorage {
+            db: SqlitePool::connect(url).await?,
+        })
+    }
+
+    async fn table_exists(&self, name: &str) -> Result<bool, sqlx::Error> {
+        Ok(sqlx::query_scalar::<_, i64>(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?",
+        )
+        .bind(name)
+        .fetch_one(&self.db)
+        .await?
+            > 0)
+    }
+}
+
+async fn record<'e>(
+    executor: impl Executor<'e, Database = Sqlite>,
+    migration: &Migration,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO schema_version (version, description, applied_at) VALUES (?, ?, ?);")
+        .bind(migration.version)
+        .bind(migration.description)
+        .bind(now())
+        .execute(executor)
+        .await?;
+    Ok(())
+}
+
+fn is_unique_violation(err: &sqlx::Error) -> bool {
+    matches!(err, sqlx::Error::Database(err) if err.message().contains("UNIQUE constraint failed"))
+}
+
+impl Storage for SqliteStorage {
+    fn user_by_name<'a>(&'a self, name: &'a str) -> StorageFuture<'a, Option<User>> {
+        Box::pin(timed(
+            "user_by_name",
+            sqlx::query_as::<_, User>("SELECT * FROM users WHERE name = ?")
+                .bind(name)
+                .fetch_optional(&self.db),
+        ))
+    }
+
+    fn user_by_id(&self, id: i64) -> StorageFuture<'_, Option<User>> {
+        Box::pin(timed(
+            "user_by_id",
+            sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+                .bind(id)
+                .fetch_optional(&self.db),
+        ))
+    }
+
+    fn create_user<'a>(&'a self, name: &'a str, password: &'a str) -> StorageFuture<'a, bool> {
+        Box::pin(async move {
+            let mut tx = self.db.begin().await?;
+            timed(
+                "insert_user",
+                sqlx::query("UPDATE user_ids SET last_id = last_id + 1;").execute(&mut tx),
+            )
+            .await?;
+            let inserted = timed("insert_user", sqlx::query("INSERT INTO users (id, name, password, role) VALUES ((SELECT last_id FROM user_ids), ?, ?, CASE WHEN EXISTS (SELECT 1 FROM users) THEN 'member' ELSE 'owner' END);")
+                .bind(name)
+                .bind(password)
+                .execute(&mut tx)).await;
+            match inserted {
+                Ok(_) => tx.commit().await.map(|_| true),
+                Err(err) if is_unique_violation(&err) => Ok(false),
+                Err(err) => Err(err),
+            }
+        })
+    }
+
+    fn token_version(&self, user_id: i64) -> StorageFuture<'_, Option<i64>> {
+        Box::pin(timed(
+            "token_version",
+            sqlx::query_scalar("SELECT token_version FROM users WHERE id = ?")
+                .bind(user_id)
+                .fetch_optional(&self.db),
+        ))
+    }
+
+    fn user_role(&self, user_id: i64) -> StorageFuture<'_, Role> {
+        Box::pin(async move {
+            let role = timed(
+                "user_role",
+                sqlx::query_scalar::<_, String>("SELECT role FROM users WHERE id = ?")
+                    .bind(user_id)
+                    .fetch_optional(&self.db),
+            )
+            .await?;
+            Ok(role
+                .and_then(|role| Role::parse(&role))
+                .unwrap_or(Role::Guest))
+        })
+    }
+
+    fn list_users(&self) -> StorageFuture<'_, Vec<UserSummary>> {
+        Box::pin(async move {
+            Ok(timed(
+                "list_users",
+                sqlx::query_as::<_, UserRow>("SELECT id, name, role FROM users ORDER BY id")
+                    .fetch_all(&self.db),
+            )
+            .await?
+            .into_iter()
+            .map(UserSummary::from)
+            .collect())
+        })
+    }
+
+    fn delete_user(&self, user_id: i64) -> StorageFuture<'_, ()> {
+        Box::pin(async move {
+            let mut tx = self.db.begin().await?;
+            // Rows pointing at the user go first
+            for (query, binds) in [
+                ("DELETE FROM offline_queue WHERE user_id = ? OR message_id IN (SELECT id FROM messages WHERE user_id = ?);", 2),
+                ("DELETE FROM read_markers WHERE user_id = ?;", 1),
+                ("DELETE FROM bans WHERE user_id = ? OR banned_by = ?;", 2),
+                ("DELETE FROM mutes WHERE user_id = ? OR muted_by = ?;", 2),
+                ("DELETE FROM messages WHERE user_id = ?;", 1),
+                ("DELETE FROM users WHERE id = ?;", 1),
+            ] {
+                let mut query = sqlx::query(query);
+                for _ in 0..binds {
+                    query = query.bind(user_id);
+                }
+                timed("delete_user", query.execute(&mut tx)).await?;
+            }
+            tx.commit().await
+        })
+    }
+
+    fn set_password<'a>(&'a self, user_id: i64, password: &'a str) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            timed(
+                "set_password",
+                sqlx::query(
+                    "UPDATE users SET password = ?, token_version = token_version + 1 WHERE id = ?;",
+                )
+                    .bind(password)
+                    .bind(user_id)
+                    .execute(&self.db),
+            )
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn set_role(&self, user_id: i64, role: Role) -> StorageFuture<'_, ()> {
+        Box::pin(async move {
+            timed(
+                "set_role",
+                sqlx::query("UPDATE users SET role = ? WHERE id = ?;")
+                    .bind(role.as_str())
+                    .bind(user_id)
+                    .execute(&self.db),
+            )
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn active_ban(&self, user_id: i64, now: i64) -> StorageFuture<'_, Option<Ban>> {
+        Box::pin(timed(
+            "active_ban",
+            sqlx::query_as::<_, Ban>(
+                "SELECT reason, until FROM bans WHERE user_id = ? AND (until IS NULL OR until > ?)",
+            )
+            .bind(user_id)
+            .bind(now)
+            .fetch_optional(&self.db),
+        ))
+    }
+
+    fn ban_user<'a>(
+        &'a self,
+        user_id: i64,
+        reason: &'a str,
+        until: Option<i64>,
+        banned_by: Option<i64>,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            timed(
+                "ban_user",
+                sqlx::query(
+                    "INSERT OR REPLACE INTO bans (user_id, reason, until, banned_by) VALUES (?, ?, ?, ?);",
+                )
+                .bind(user_id)
+                .bind(reason)
+                .bind(until)
+                .bind(banned_by)
+                .execute(&self.db),
+            )
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn unban_user(&self, user_id: i64) -> StorageFuture<'_, bool> {
+        Box::pin(async move {
+            let deleted = timed(
+                "unban_user",
+                sqlx::query("DELETE FROM bans WHERE user_id = ?;")
+                    .bind(user_id)
+                    .execute(&self.db),
+            )
+            .await?;
+            Ok(deleted.rows_affected() > 0)
+        })
+    }
+
+    fn muted_until(&self, user_id: i64, now: i64) -> StorageFuture<'_, Option<i64>> {
+        Box::pin(timed(
+            "muted_until",
+            sqlx::query_scalar::<_, i64>("SELECT until FROM mutes WHERE user_id = ? AND until > ?")
+                .bind(user_id)
+                .bind(now)
+                .fetch_optional(&self.db),
+        ))
+    }
+
+    fn mute_user(&self, user_id: i64, until: i64, muted_by: i64) -> StorageFuture<'_, ()> {
+        Box::pin(async move {
+            timed(
+                "mute_user",
+                sqlx::query(
+                    "INSERT OR REPLACE INTO mutes (user_id, until, muted_by) VALUES (?, ?, ?);",
+                )
+                .bind(user_id)
+                .bind(until)
+                .bind(muted_by)
+                .execute(&self.db),
+            )
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn insert_message<'a>(&'a self, msg: NewMessage<'a>) -> StorageFuture<'a, i64> {
+        Box::pin(async move {
+            Ok(timed("insert_message", sqlx::query("INSERT INTO messages (room, user_id, username, recipient, data) VALUES (?, ?, ?, ?, ?);")
+                .bind(msg.room)
+                .bind(msg.user_id)
+                .bind(msg.username)
+                .bind(msg.recipient)
+                .bind(msg.data)
+                .execute(&self.db)).await?.last_insert_rowid())
+        })
+    }
+
+    fn message_owner(&self, message_id: i64) -> StorageFuture<'_, Option<i64>> {
+        Box::pin(timed(
+            "message_owner",
+            sqlx::query_scalar::<_, i64>("SELECT user_id FROM messages WHERE id = ?")
+                .bind(message_id)
+                .fetch_optional(&self.db),
+        ))
+    }
+
+    fn delete_message(&self, message_id: i64) -> StorageFuture<'_, bool> {
+        Box::pin(async move {
+            timed(
+                "delete_message",
+                sqlx::query("DELETE FROM offline_queue WHERE message_id = ?;")
+                    .bind(message_id)
+                    .execute(&self.db),
+            )
+            .await?;
+            let deleted = timed(
+                "delete_message",
+                sqlx::query("DELETE FROM messages WHERE id = ?;")
+                    .bind(message_id)
+                    .execute(&self.db),
+            )
+            .await?;
+            Ok(deleted.rows_affected() > 0)
+        })
+    }
+
+    fn delete_room_messages<'a>(&'a self, room: &'a str) -> StorageFuture<'a, u64> {
+        Box::pin(async move {
+            timed("delete_room_messages", sqlx::query(
+                "DELETE FROM offline_queue WHERE message_id IN (SELECT id FROM messages WHERE recipient IS NULL AND room = ?);",
+            )
+            .bind(room)
+            .execute(&self.db)
+            ).await?;
+            let deleted = timed(
+                "delete_room_messages",
+                sqlx::query("DELETE FROM messages WHERE recipient IS NULL AND room = ?;")
+                    .bind(room)
+                    .execute(&self.db),
+            )
+            .await?;
+            Ok(deleted.rows_affected())
+        })
+    }
+
+    fn delete_user_messages(&self, user_id: i64) -> StorageFuture<'_, u64> {
+        Box::pin(async move {
+            timed("delete_user_messages", sqlx::query(
+                "DELETE FROM offline_queue WHERE message_id IN (SELECT id FROM messages WHERE user_id = ?);",
+            )
+            .bind(user_id)
+            .execute(&self.db)
+            ).await?;
+            let deleted = timed(
+                "delete_user_messages",
+                sqlx::query("DELETE FROM messages WHERE user_id = ?;")
+                    .bind(user_id)
+                    .execute(&self.db),
+            )
+            .await?;
+            Ok(deleted.rows_affected())
+        })
+    }
+
+    fn unread_counts(&self, user_id: i64) -> StorageFuture<'_, Vec<UnreadCount>> {
+        Box::pin(async move {
+            Ok(timed("unread_counts", sqlx::query_as::<_, UnreadRow>(
+                "SELECT c.room AS room, COUNT(c.id) AS count, COALESCE(r.last_read_id, 0) AS last_read_id,
+                  MAX(c.id) AS newest_id
+                FROM (
+                  SELECT id, CASE WHEN recipient IS NULL THEN room ELSE '@' || username END AS room
+                  FROM messages
+                  WHERE user_id != ? AND (recipient IS NULL OR recipient = (SELECT name FROM users WHERE id = ?))
+                ) c
+                LEFT JOIN read_markers r ON r.room = c.room AND r.user_id = ?
+                WHERE c.id > COALESCE(r.last_read_id, 0)
+                GROUP BY c.room",
+            )
+            .bind(user_id)
+            .bind(user_id)
+            .bind(user_id)
+            .fetch_all(&self.db)
+            ).await?
+            .into_iter()
+            .map(UnreadCount::from)
+            .collect())
+        })
+    }
+
+    fn set_read_marker<'a>(
+        &'a self,
+        user_id: i64,
+        room: &'a str,
+        last_read_id: i64,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            timed("set_read_marker", sqlx::query(
+                "INSERT INTO read_markers (user_id, room, last_read_id) VALUES (?, ?, ?)
+                ON CONFLICT (user_id, room) DO UPDATE SET last_read_id = MAX(last_read_id, excluded.last_read_id);",
+            )
+            .bind(user_id)
+            .bind(room)
+            .bind(last_read_id)
+            .execute(&self.db)
+            ).await?;
+            Ok(())
+        })
+    }
+
+    fn queue_offline<'a>(
+        &'a self,
+        username: &'a str,
+        message_id: i64,
+        kind: &'a str,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            timed("queue_offline", sqlx::query(
+                "INSERT INTO offline_queue (user_id, message_id, kind) SELECT id, ?, ? FROM users WHERE name = ?;",
+            )
+            .bind(message_id)
+            .bind(kind)
+            .bind(username)
+            .execute(&self.db)
+            ).await?;
+            Ok(())
+        })
+    }
+
+    fn messages_since(&self, user_id: i64, last_seen_id: i64) -> StorageFuture<'_, Vec<ServerMsg>> {
+        Box::pin(async move {
+            let mut rows = timed("messages_since", sqlx::query_as::<_, MessageRow>(
+                "SELECT id, username, room, recipient, data FROM messages
+                WHERE id > ? AND (recipient IS NULL OR user_id = ? OR recipient = (SELECT name FROM users WHERE id = ?))
+                ORDER BY id DESC
+                LIMIT ?",
+            )
+            .bind(last_seen_id)
+            .bind(user_id)
+            .bind(user_id)
+            .bind(REPLAY_LIMIT)
+            .fetch_all(&self.db)
+            ).await?;
+            rows.reverse();
+
+            Ok(rows
+                .into_iter()
+                .filter_map(MessageRow::into_server_msg)
+                .collect())
+        })
+    }
+
+    fn take_offline_queue(&self, user_id: i64) -> StorageFuture<'_, Vec<(String, ServerMsg)>> {
+        Box::pin(async move {
+            let rows = timed("take_offline_queue", sqlx::query_as::<_, QueuedRow>(
+                "SELECT q.id AS queue_id, q.kind AS kind, m.id AS id, m.username AS username, m.room AS room, m.recipient AS recipient, m.data AS data
+                FROM offline_queue q
+                JOIN messages m ON m.id = q.message_id
+                WHERE q.user_id = ?
+                ORDER BY m.id",
+            )
+            .bind(user_id)
+            .fetch_all(&self.db)
+            ).await?;
+
+            // Only the fetched rows, anything queued meanwhile waits for the next login
+            let last_queue_id = rows.iter().map(|row| row.queue_id).max().unwrap_or(0);
+            timed(
+                "take_offline_queue",
+                sqlx::query("DELETE FROM offline_queue WHERE user_id = ? AND id <= ?;")
+                    .bind(user_id)
+                    .bind(last_queue_id)
+                    .execute(&self.db),
+            )
+            .await?;
+
+            Ok(rows
+                .into_iter()
+                .filter_map(QueuedRow::into_queued)
+                .collect())
+        })
+    }
+
+    fn failed_attempts<'a>(&'a self, key: &'a str, since: i64) -> StorageFuture<'a, i64> {
+        Box::pin(async move {
+            timed(
+                "failed_attempts",
+                sqlx::query_scalar::<_, i64>(
+                    "SELECT failures FROM login_attempts WHERE key = ? AND last_failure > ?",
+                )
+                .bind(key)
+                .bind(since)
+                .fetch_optional(&self.db),
+            )
+            .await
+            .map(|failures| failures.unwrap_or(0))
+        })
+    }
+
+    fn add_failed_attempt<'a>(
+        &'a self,
+        key: &'a str,
+        now: i64,
+        since: i64,
+    ) -> StorageFuture<'a, i64> {
+        Box::pin(async move {
+            timed(
+                "add_failed_attempt",
+                sqlx::query(
+                    "INSERT INTO login_attempts (key, failures, last_failure) VALUES (?, 1, ?)
+                ON CONFLICT(key) DO UPDATE SET
+                failures = CASE WHEN last_failure > ? THEN failures + 1 ELSE 1 END,
+                last_failure = excluded.last_failure;",
+                )
+                .bind(key)
+                .bind(now)
+                .bind(since)
+                .execute(&self.db),
+            )
+            .await?;
+            self.failed_attempts(key, since).await
+        })
+    }
+
+    fn clear_failed_attempts<'a>(&'a self, key: &'a str) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            timed(
+                "clear_failed_attempts",
+                sqlx::query("DELETE FROM login_attempts WHERE key = ?;")
+                    .bind(key)
+                    .execute(&self.db),
+            )
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn lock_out<'a>(&'a self, key: &'a str, until: i64) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            timed(
+                "lock_out",
+                sqlx::query("UPDATE login_attempts SET locked_until = ? WHERE key = ?;")
+                    .bind(until)
+                    .bind(key)
+                    .execute(&self.db),
+            )
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn locked_until<'a>(&'a self, key: &'a str, now: i64) -> StorageFuture<'a, Option<i64>> {
+        Box::pin(timed(
+            "locked_until",
+            sqlx::query_scalar::<_, i64>(
+                "SELECT locked_until FROM login_attempts WHERE key = ? AND locked_until > ?",
+            )
+            .bind(key)
+            .bind(now)
+            .fetch_optional(&self.db),
+        ))
+    }
+
+    fn audit<'a>(
+        &'a self,
+        event: &'a str,
+        username: Option<&'a str>,
+        address: &'a str,
+        now: i64,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            timed(
+                "audit",
+                sqlx::query(
+                    "INSERT INTO audit_log (event, username, address, created_at) VALUES (?, ?, ?, ?);",
+                )
+                .bind(event)
+                .bind(username)
+                .bind(address)
+                .bind(now)
+                .execute(&self.db),
+            )
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn stats(&self, now: i64) -> StorageFuture<'_, Stats> {
+        Box::pin(timed(
+            "stats",
+            sqlx::query_as::<_, Stats>(
+                "SELECT
+                (SELECT COUNT(*) FROM users) AS users,
+                (SELECT COUNT(*) FROM messages) AS messages,
+                (SELECT COUNT(*) FROM bans WHERE until IS NULL OR until > ?) AS active_bans,
+                (SELECT COUNT(*) FROM mutes WHERE until > ?) AS active_mutes",
+            )
+            .bind(now)
+            .bind(now)
+            .fetch_one(&self.db),
+        ))
+    }
+
+    // Databases made before migrations have no schema_version table, running their
+    // CREATE TABLE IF NOT EXISTS again is harmless but the role column may be there already
+    fn applied_migrations(&self) -> StorageFuture<'_, Vec<i64>> {
+        Box::pin(async move {
+            if !self.table_exists("schema_version").await? {
+                let legacy = self.table_exists("users").await?;
+                sqlx::query(SCHEMA_VERSION_TABLE).execute(&self.db).await?;
+                let has_role = sqlx::query_scalar::<_, i64>(
+                    "SELECT COUNT(*) FROM pragma_table_info('users') WHERE name = 'role'",
+                )
+                .fetch_one(&self.db)
+                .await?
+                    > 0;
+                if legacy && has_role {
+                    let migration = MIGRATIONS
+                        .iter()
+                        .find(|migration| migration.version == ROLE_VERSION)
+                        .unwrap();
+                    record(&self.db, migration).await?;
+                    info!("adopted a database made before migrations");
+                }
+            }
+            sqlx::query_scalar("SELECT version FROM schema_version ORDER BY version")
+                .fetch_all(&self.db)
+                .await
+        })
+    }
+
+    fn apply_migration(&self, migration: &'static Migration) -> StorageFuture<'_, ()> {
+        Box::pin(async move {
+            let mut tx = self.db.begin().await?;
+            sqlx::query(migration.sqlite).execute(&mut tx).await?;
+            record(&mut tx, migration).await?;
+            tx.commit().await
+        })
+    }
+
+    fn close(&self) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(self.db.close())
+    }
+}
@@ -0,0 +1,294 @@
+use super::{
+    memory::MemoryStorage, postgres::PostgresStorage, sqlite::SqliteStorage, NewMessage, Storage,
+    QUEUE_DIRECT, QUEUE_MENTION,
+};
+use crate::migrations::{self, MIGRATIONS};
+use shared_utils::{encode_msg_data, MsgDataType, Role};
+
+// Every storage passes the same checks, starting from an empty database
+async fn check(db: &dyn Storage) {
+    assert_eq!(migrations::run(db).await.unwrap(), MIGRATIONS.len());
+    assert_eq!(migrations::run(db).await.unwrap(), 0);
+    let versions: Vec<i64> = MIGRATIONS
+        .iter()
+        .map(|migration| migration.version)
+        .collect();
+    assert_eq!(db.applied_migrations().await.unwrap(), versions);
+
+    users(db).await;
+    moderation(db).await;
+    messages(db).await;
+    login_attempts(db).await;
+    db.close().await;
+}
+
+async fn user(db: &dyn Storage, name: &str) -> i64 {
+    assert!(db.create_user(name, "hash").await.unwrap());
+    db.user_by_name(name).await.unwrap().unwrap().id
+}
+
+async fn users(db: &dyn Storage) {
+    assert!(db.create_user("alice", "hash-a").await.unwrap());
+    assert!(db.create_user("bob", "hash-b").await.unwrap());
+    assert!(!db.create_user("bob", "hash-c").await.unwrap());
+    let alice = db.user_by_name("alice").await.unwrap().unwrap();
+    let bob = db.user_by_name("bob").await.unwrap().unwrap();
+    assert_eq!(alice.password, "hash-a");
+    assert_eq!(db.user_by_id(bob.id).await.unwrap().unwrap().name, "bob");
+    assert!(db.user_by_name("nobody").await.unwrap().is_none());
+
+    // The first user owns the server, unknown users are guests
+    assert_eq!(db.user_role(alice.id).await.unwrap(), Role::Owner);
+    assert_eq!(db.user_role(bob.id).await.unwrap(), Role::Member);
+    assert_eq!(db.user_role(-1).await.unwrap(), Role::Guest);
+    db.set_role(bob.id, Role::Moderator).await.unwrap();
+    let users: Vec<(String, Role)> = db
+        .list_users()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|user| (user.name, user.role))
+        .collect();
+    assert_eq!(
+        users,
+        [
+            ("alice".to_string(), Role::Owner),
+            ("bob".to_string(), Role::Moderator)
+        ]
+    );
+
+    // A new password revokes the tokens
+    let version = db.token_version(bob.id).await.unwrap().unwrap();
+    db.set_password(bob.id, "hash-c").await.unwrap();
+    assert_eq!(
+        db.user_by_id(bob.id).await.unwrap().unwrap().password,
+        "hash-c"
+    );
+    assert_eq!(db.token_version(bob.id).await.unwrap(), Some(version + 1));
+
+    // The id of a deleted user isn't given again
+    db.delete_user(bob.id).await.unwrap();
+    assert!(db.user_by_id(bob.id).await.unwrap().is_none());
+    assert_eq!(db.token_version(bob.id).await.unwrap(), None);
+    assert!(user(db, "carol").await > bob.id);
+}
+
+async fn moderation(db: &dyn Storage) {
+    let moderator = user(db, "moderator").await;
+    let target = user(db, "target").await;
+
+    assert!(db.active_ban(target, 100).await.unwrap().is_none());
+    db.ban_user(target, "spam", Some(200), Some(moderator))
+        .await
+        .unwrap();
+    let ban = db.active_ban(target, 100).await.unwrap().unwrap();
+    assert_eq!((ban.reason.as_str(), ban.until), ("spam", Some(200)));
+    assert!(db.active_ban(target, 200).await.unwrap().is_none());
+    // A new ban replaces the old one, the console bans without a moderator
+    db.ban_user(target, "flood", None, None).await.unwrap();
+    let ban = db.active_ban(target, 1000).await.unwrap().unwrap();
+    assert_eq!((ban.reason.as_str(), ban.until), ("flood", None));
+    assert_eq!(db.stats(100).await.unwrap().active_bans, 1);
+    assert!(db.unban_user(target).await.unwrap());
+    assert!(!db.unban_user(target).await.unwrap());
+
+    db.mute_user(target, 200, moderator).await.unwrap();
+    assert_eq!(db.muted_until(target, 100).await.unwrap(), Some(200));
+    assert_eq!(db.muted_until(target, 200).await.unwrap(), None);
+    assert_eq!(db.stats(100).await.unwrap().active_mutes, 1);
+
+    // Deleting the moderator lifts the bans and mutes they gave
+    db.ban_user(target, "spam", None, Some(moderator))
+        .await
+        .unwrap();
+    db.delete_user(moderator).await.unwrap();
+    assert!(db.active_ban(target, 100).await.unwrap().is_none());
+    assert_eq!(db.muted_until(target, 100).await.unwrap(), None);
+}
+
+fn text(text: &str) -> Vec<u8> {
+    encode_msg_data(&MsgDataType::Text(text.to_string()))
+}
+
+async fn messages(db: &dyn Storage) {
+    let ann = user(db, "ann").await;
+    let ben = user(db, "ben").await;
+    let cid = user(db, "cid").await;
+    let first = db
+        .insert_message(NewMessage {
+            room: "general",
+            user_id: ann,
+            username: "ann",
+            recipient: None,
+            data: text("hi @ben"),
+        })
+        .await
+        .unwrap();
+    let direct = db
+        .insert_message(NewMessage {
+            room: "general",
+            user_id: ann,
+            username: "ann",
+            recipient: Some("ben"),
+            data: text("psst"),
+        })
+        .await
+        .unwrap();
+    let own = db
+        .insert_message(NewMessage {
+            room: "rust",
+            user_id: ben,
+            username: "ben",
+            recipient: None,
+            data: text("hello"),
+        })
+        .await
+        .unwrap();
+    assert!(first < direct && direct < own);
+    assert_eq!(db.message_owner(direct).await.unwrap(), Some(ann));
+    assert_eq!(db.message_owner(-1).await.unwrap(), None);
+    assert_eq!(db.stats(100).await.unwrap().messages, 3);
+
+    // Direct msgs are counted in a room named after the sender, the own ones never are
+    let unread = |user_id| async move {
+        let mut counts: Vec<(String, i64, i64, i64)> = db
+            .unread_counts(user_id)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|count| (count.room, count.count, count.last_read_id, count.newest_id))
+            .collect();
+        counts.sort();
+        counts
+    };
+    assert_eq!(
+        unread(ben).await,
+        [
+            ("@ann".to_string(), 1, 0, direct),
+            ("general".to_string(), 1, 0, first)
+        ]
+    );
+    // Read markers only move forward
+    db.set_read_marker(ben, "general", first).await.unwrap();
+    db.set_read_marker(ben, "general", 0).await.unwrap();
+    assert_eq!(unread(ben).await, [("@ann".to_string(), 1, 0, direct)]);
+    assert_eq!(unread(cid).await.len(), 2);
+
+    // Direct msgs are replayed only to their sender and recipient
+    let since = |user_id, last_seen_id| async move {
+        db.messages_since(user_id, last_seen_id)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|msg| msg.id)
+            .collect::<Vec<i64>>()
+    };
+    assert_eq!(since(ann, 0).await, [first, direct, own]);
+    assert_eq!(since(ben, first).await, [direct, own]);
+    assert_eq!(since(cid, 0).await, [first, own]);
+    let replayed = db.messages_since(ben, first).await.unwrap();
+    assert_eq!(replayed[0].username, "ann");
+    assert_eq!(replayed[0].to.as_deref(), Some("ben"));
+    assert!(matches!(&replayed[0].data, MsgDataType::Text(text) if text == "psst"));
+
+    // Unknown users get nothing queued, the queue is emptied when taken
+    db.queue_offline("ben", direct, QUEUE_DIRECT).await.unwrap();
+    db.queue_offline("ben", first, QUEUE_MENTION).await.unwrap();
+    db.queue_offline("nobody", first, QUEUE_MENTION)
+        .await
+        .unwrap();
+    let queued: Vec<(String, i64)> = db
+        .take_offline_queue(ben)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|(kind, msg)| (kind, msg.id))
+        .collect();
+    assert_eq!(
+        queued,
+        [
+            (QUEUE_MENTION.to_string(), first),
+            (QUEUE_DIRECT.to_string(), direct)
+        ]
+    );
+    assert!(db.take_offline_queue(ben).await.unwrap().is_empty());
+
+    // Deleted msgs leave the queue too
+    assert!(db.delete_message(direct).await.unwrap());
+    assert!(!db.delete_message(direct).await.unwrap());
+    db.queue_offline("ben", first, QUEUE_MENTION).await.unwrap();
+    assert_eq!(db.delete_room_messages("general").await.unwrap(), 1);
+    assert!(db.take_offline_queue(ben).await.unwrap().is_empty());
+    assert_eq!(db.delete_user_messages(ben).await.unwrap(), 1);
+    assert_eq!(db.stats(100).await.unwrap().messages, 0);
+}
+
+async fn login_attempts(db: &dyn Storage) {
+    let key = "ip:127.0.0.1";
+    assert_eq!(db.failed_attempts(key, 0).await.unwrap(), 0);
+    assert_eq!(db.add_failed_attempt(key, 100, 50).await.unwrap(), 1);
+    assert_eq!(db.add_failed_attempt(key, 110, 50).await.unwrap(), 2);
+    assert_eq!(db.failed_attempts(key, 50).await.unwrap(), 2);
+    // Failures older than since don't count, the next one starts over
+    assert_eq!(db.failed_attempts(key, 110).await.unwrap(), 0);
+    assert_eq!(db.add_failed_attempt(key, 200, 150).await.unwrap(), 1);
+
+    db.lock_out(key, 300).await.unwrap();
+    assert_eq!(db.locked_until(key, 250).await.unwrap(), Some(300));
+    assert_eq!(db.locked_until(key, 300).await.unwrap(), None);
+    db.clear_failed_attempts(key).await.unwrap();
+    assert_eq!(db.failed_attempts(key, 0).await.unwrap(), 0);
+    assert_eq!(db.locked_until(key, 250).await.unwrap(), None);
+
+    db.audit("login_failed", Some("ann"), "127.0.0.1", 100)
+        .await
+        .unwrap();
+    db.audit("signup_failed", None, "127.0.0.1", 100)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn memory() {
+    check(&MemoryStorage::default()).await;
+}
+
+#[tokio::test]
+async fn sqlite() {
+    let path = std::env::temp_dir().join(format!("chat-console-test-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let db = SqliteStorage::connect(&format!("sqlite://{}", path.display()))
+        .await
+        .unwrap();
+    check(&db).await;
+    let _ = std::fs::remove_file(&path);
+}
+
+// Needs TEST_DATABASE_URL, skipped without it. Runs in a schema of its own, dropped at the end
+#[tokio::test]
+async fn postgres() {
+    let Ok(url) = std::env::var("TEST_DATABASE_URL") else {
+        eprintln!("TEST_DATABASE_URL is unset, skipping the postgres storage test");
+        return;
+    };
+    let schema = format!("chat_console_test_{}", std::process::id());
+    let admin = sqlx::PgPool::connect(&url).await.unwrap();
+    for query in [
+        format!("DROP SCHEMA IF EXISTS {} CASCADE", schema),
+        format!("CREATE SCHEMA {}", schema),
+    ] {
+        sqlx::query(&query).execute(&admin).await.unwrap();
+    }
+    let separator = if url.contains('?') { '&' } else { '?' };
+    let db = PostgresStorage::connect(&format!(
+        "{}{}options[search_path]={}",
+        url, separator, schema
+    ))
+    .await
+    .unwrap();
+    check(&db).await;
+    sqlx::query(&format!("DROP SCHEMA {} CASCADE", schema))
+        .execute(&admin)
+        .await
+        .unwrap();
+}
@@ -0,0 +1,139 @@
+use crate::{handlers::now, storage::Storage};
+use hmac::{Hmac, Mac};
+use jwt::{SignWithKey, VerifyWithKey};
+use sha2::Sha256;
+use std::{collections::BTreeMap, fmt, io, path::Path};
+use tracing::{debug, info};
+
+// Seconds a token is valid for
+const TOKEN_LIFETIME: i64 = 30 * 24 * 60 * 60;
+// Where the signing secret is kept when JWT_SECRET is unset, overridden by SECRET_PATH
+const DEFAULT_SECRET_PATH: &str = "chat-console.secret";
+// Random bytes of a generated secret. Given ones need at least as many characters, which
+// also refuses the "SECRETO" every old server used
+const SECRET_SIZE: usize = 32;
+
+#[derive(Debug)]
+pub enum SecretError {
+    Io(io::Error),
+    TooShort(usize),
+}
+
+impl fmt::Display for SecretError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecretError::Io(err) => write!(f, "couldn't read the token secret: {}", err),
+            SecretError::TooShort(len) => write!(
+                f,
+                "the token secret has {} characters, it needs at least {}",
+                len, SECRET_SIZE
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SecretError {}
+
+impl From<io::Error> for SecretError {
+    fn from(err: io::Error) -> Self {
+        SecretError::Io(err)
+    }
+}
+
+// Hex encoded random bytes
+pub fn random_hex(size: usize) -> io::Result<String> {
+    let mut bytes = vec![0u8; size];
+    getrandom::getrandom(&mut bytes).map_err(|err| io::Error::other(err.to_string()))?;
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+// Creates the file, readable only by its owner on unix. An existing one is an error
+pub fn write_private(path: &Path, contents: &str) -> io::Result<()> {
+    use std::{fs::OpenOptions, io::Write};
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(contents.as_bytes())
+}
+
+// Signs and checks the session tokens
+pub struct Tokens {
+    key: Hmac<Sha256>,
+}
+
+impl Tokens {
+    // The secret is JWT_SECRET, or the one in the secret file, made on the first start
+    pub fn load() -> Result<Tokens, SecretError> {
+        let secret = match std::env::var("JWT_SECRET") {
+            Ok(secret) => secret,
+            Err(_) => {
+                let path = std::env::var("SECRET_PATH")
+                    .unwrap_or_else(|_| DEFAULT_SECRET_PATH.to_string());
+                match std::fs::read_to_string(&path) {
+                    Ok(secret) => secret.trim().to_string(),
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                        let secret = random_hex(SECRET_SIZE)?;
+                        write_private(Path::new(&path), &secret)?;
+                        info!(%path, "token secret created");
+                        secret
+                    }
+                    Err(err) => return Err(err.into()),
+                }
+            }
+        };
+        if secret.len() < SECRET_SIZE {
+            return Err(SecretError::TooShort(secret.len()));
+        }
+        Ok(Tokens::new(&secret))
+    }
+
+    pub fn new(secret: &str) -> Tokens {
+        Tokens {
+            key: Hmac::new_from_slice(secret.as_bytes()).expect("hmac takes keys of any size"),
+        }
+    }
+
+    // A new token for the user and its expiration time
+    pub async fn issue(
+        &self,
+        db: &dyn Storage,
+        user_id: i64,
+    ) -> Result<(String, i64), sqlx::Error> {
+        let version = db.token_version(user_id).await?.unwrap_or(0);
+        Ok(self.sign(user_id, version))
+    }
+
+    // Id of the user the token belongs to, if it's signed, not expired and not revoked
+    pub async fn user_id(&self, db: &dyn Storage, token: &str) -> Result<Option<i64>, sqlx::Error> {
+        let Some((user_id, version)) = self.verify(token) else {
+            return Ok(None);
+        };
+        Ok((db.token_version(user_id).await? == Some(version)).then_some(user_id))
+    }
+
+    fn sign(&self, user_id: i64, version: i64) -> (String, i64) {
+        let expires_at = now() + TOKEN_LIFETIME;
+        let mut claims = BTreeMap::new();
+        claims.insert("id", user_id);
+        claims.insert("ver", version);
+        claims.insert("exp", expires_at);
+        (claims.sign_with_key(&self.key).unwrap(), expires_at)
+    }
+
+    // Id of the user the token belongs to and its version, if it's signed and not expired
+    fn verify(&self, token: &str) -> Option<(i64, i64)> {
+        let claims: BTreeMap<String, i64> = match token.verify_with_key(&self.key) {
+            Ok(claims) => claims,
+            Err(err) => {
+                debug!(%err, "invalid token");
+                return None;
+            }
+        };
+        if *claims.get("exp")? < now() {
+            return None;
+        }
+        Some((*claims.get("id")?, *claims.get("ver")?))
+    }
+}
@@ -4,6 +4,68 @@ extern crate alloc;
 use alloc::vec::Vec;
 
 pub const MSG_SIZE_BYTES: usize = std::mem::size_of::<u32>();
+pub const DEFAULT_ROOM: &str = "general";
+// Clients ping the server this often, so it can tell idle connections from dead ones
+pub const PING_INTERVAL: std::time::Duration = std::time::Duration::from_secs(15);
+// Longest mute in seconds, a year
+pub const MAX_MUTE: i64 = 365 * 24 * 60 * 60;
+
+// Roles from the least to the most privileged
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Guest,
+    Member,
+    Moderator,
+    Admin,
+    Owner,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    SendMessage,
+    UploadImage,
+    DeleteOthersMessages,
+    ManageRooms,
+    Kick,
+    Mute,
+    Ban,
+}
+
+impl Role {
+    pub const ALL: [Role; 5] = [
+        Role::Guest,
+        Role::Member,
+        Role::Moderator,
+        Role::Admin,
+        Role::Owner,
+    ];
+
+    // The permission matrix, shared so the client can hide what the server would refuse
+    pub fn can(self, permission: Permission) -> bool {
+        match permission {
+            Permission::SendMessage | Permission::UploadImage => self >= Role::Member,
+            Permission::DeleteOthersMessages
+            | Permission::Kick
+            | Permission::Mute
+            | Permission::Ban => self >= Role::Moderator,
+            Permission::ManageRooms => self >= Role::Admin,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Guest => "guest",
+            Role::Member => "member",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+            Role::Owner => "owner",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Role> {
+        Role::ALL.into_iter().find(|role| role.as_str() == name)
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum MsgDataType {
@@ -13,17 +75,69 @@ pub enum MsgDataType {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct UserMsg {
-    pub username: String,
+    pub room: String,
+    // Recipient of a direct msg, the room is ignored when set
+    pub to: Option<String>,
     pub data: MsgDataType,
     pub token: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ServerMsg {
+    pub id: i64,
     pub username: String,
+    pub room: String,
+    pub to: Option<String>,
     pub data: MsgDataType,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReadMarkerMsg {
+    pub token: String,
+    pub room: String,
+    pub last_read_id: i64,
+}
+
+// Log in again with a stored token after a reconnect, the msgs after
+// last_seen_id are sent again
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResumeMsg {
+    pub token: String,
+    pub last_seen_id: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeleteMsg {
+    pub token: String,
+    pub id: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ModerationAction {
+    Kick,
+    // Seconds, from 1 up to MAX_MUTE
+    Mute { duration: i64 },
+    // Unix time in seconds, forever when none
+    Ban { reason: String, until: Option<i64> },
+}
+
+// A moderation action against a user with a lower role
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ModerationMsg {
+    pub token: String,
+    pub username: String,
+    pub action: ModerationAction,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UnreadCount {
+    pub room: String,
+    pub count: i64,
+    pub last_read_id: i64,
+    // Id of the newest msg in the room, marking it read clears the count
+    pub newest_id: i64,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct LoginMsg {
     pub username: String,
@@ -34,13 +148,154 @@ pub struct LoginMsg {
 pub struct TokenMsg {
     pub token: String,
     pub username: String,
+    pub role: Role,
+    // Unix time in seconds
+    pub expires_at: i64,
+}
+
+// What was queued for the user while offline
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OfflineSummaryMsg {
+    pub direct: i64,
+    pub mentions: i64,
+}
+
+// Sent to every client before the server stops
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ShutdownMsg {
+    pub reason: String,
+    // Seconds to wait before reconnecting
+    pub reconnect_after: i64,
+}
+
+// Why the server refused a request
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    // Wrong username or password on login
+    InvalidCredentials,
+    UsernameTaken,
+    UserNotFound,
+    // The token is invalid or expired, the user has to login again
+    AuthExpired,
+    Forbidden,
+    Banned,
+    Muted,
+    // Kicked out a moment ago, the user can come back after a while
+    Kicked,
+    // Too many failed logins from the account or address
+    LockedOut,
+    RateLimited,
+    // Only admins can login while the server is under maintenance
+    Maintenance,
+    MsgTooBig,
+    Malformed,
+    Internal,
+}
+
+impl ErrorCode {
+    // Shown when the error has no detail
+    pub fn text(self) -> &'static str {
+        match self {
+            ErrorCode::InvalidCredentials => "The username or password are incorrect!.",
+            ErrorCode::UsernameTaken => "User already exist!.",
+            ErrorCode::UserNotFound => "User doesn't exist!.",
+            ErrorCode::AuthExpired => "Session expired, login again.",
+            ErrorCode::Forbidden => "You don't have permission to do that.",
+            ErrorCode::Banned => "You are banned.",
+            ErrorCode::Muted => "You are muted.",
+            ErrorCode::Kicked => "You were kicked, try again later.",
+            ErrorCode::LockedOut => "Too many failed attempts, try again later.",
+            ErrorCode::RateLimited => "You are sending too fast, slow down.",
+            ErrorCode::Maintenance => "The server is under maintenance, try again later.",
+            ErrorCode::MsgTooBig => "Msg too big.",
+            ErrorCode::Malformed => "Malformed request.",
+            ErrorCode::Internal => "Internal server error, try again.",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ErrorMsg {
+    pub code: ErrorCode,
+    // Replaces the text of the code when set, e.g. with the ban reason
+    pub detail: Option<String>,
+}
+
+impl ErrorMsg {
+    pub fn new(code: ErrorCode) -> Self {
+        Self { code, detail: None }
+    }
+
+    pub fn with_detail(code: ErrorCode, detail: String) -> Self {
+        Self {
+            code,
+            detail: Some(detail),
+        }
+    }
+
+    pub fn text(&self) -> &str {
+        self.detail.as_deref().unwrap_or(self.code.text())
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum ServerRes {
-    Error(String),
+    Error(ErrorMsg),
     UserToken(TokenMsg),
     UserCreated,
+    UnreadCounts(Vec<UnreadCount>),
+    OfflineSummary(OfflineSummaryMsg),
+    MsgDeleted(i64),
+    Notice(String),
+    ServerShutdown(ShutdownMsg),
+    // The connection is closed right after, the client must not resume on its own
+    Kicked(String),
+    // Answer to a request that has no other response
+    Ack,
+}
+
+pub type RequestId = u64;
+
+// What a client can ask with a Request. It can't hold another request, a deeply nested one
+// would overflow the stack of whoever decodes it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum RequestKind {
+    MsgOut(UserMsg),
+    Login(LoginMsg),
+    Signup(LoginMsg),
+    Resume(ResumeMsg),
+    Logout,
+    Delete(DeleteMsg),
+    Moderate(ModerationMsg),
+    ReadMarker(ReadMarkerMsg),
+}
+
+impl From<RequestKind> for MsgType {
+    fn from(kind: RequestKind) -> Self {
+        match kind {
+            RequestKind::MsgOut(msg) => MsgType::MsgOut(msg),
+            RequestKind::Login(msg) => MsgType::Login(msg),
+            RequestKind::Signup(msg) => MsgType::Signup(msg),
+            RequestKind::Resume(msg) => MsgType::Resume(msg),
+            RequestKind::Logout => MsgType::Logout,
+            RequestKind::Delete(msg) => MsgType::Delete(msg),
+            RequestKind::Moderate(msg) => MsgType::Moderate(msg),
+            RequestKind::ReadMarker(msg) => MsgType::ReadMarker(msg),
+        }
+    }
+}
+
+// The server answers it with a Response carrying the same id
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RequestMsg {
+    pub id: RequestId,
+    pub msg: RequestKind,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResponseMsg {
+    pub id: RequestId,
+    pub res: ServerRes,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -49,7 +304,17 @@ pub enum MsgType {
     MsgOut(UserMsg),
     Login(LoginMsg),
     Signup(LoginMsg),
+    Resume(ResumeMsg),
+    Logout,
+    Delete(DeleteMsg),
+    Moderate(ModerationMsg),
+    ReadMarker(ReadMarkerMsg),
     Server(ServerRes),
+    Request(RequestMsg),
+    Response(ResponseMsg),
+    // Answered with a Pong carrying the same number
+    Ping(u64),
+    Pong(u64),
 }
 
 // Write the msg header and body
@@ -82,6 +347,14 @@ pub fn encode_msg_type(msg: &MsgType) -> Vec<u8> {
     buf
 }
 
+pub fn encode_msg_data(data: &MsgDataType) -> Vec<u8> {
+    to_allocvec(data).unwrap()
+}
+
+pub fn decode_msg_data(data: &[u8]) -> Result<MsgDataType, postcard::Error> {
+    from_bytes(data)
+}
+
 pub fn decode_header(data: &[u8]) -> u32 {
     let mut offset = 0;
     let mut value: u32 = 0;